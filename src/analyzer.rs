@@ -1,23 +1,43 @@
 use core::f32;
 use std::collections::VecDeque;
 use std::f64::consts::PI;
-use std::sync::{mpsc, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{mpsc, Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use linear_predictive_coding::calc_lpc_by_burg;
 use pitch_detection::detector;
 use pitch_detection::detector::PitchDetector;
 use rustfft::num_complex::Complex;
 use rustfft::num_traits::Inv;
+use serde::{Deserialize, Serialize};
 
-use crate::osc::OscSender;
-use crate::sound_device::{Capturer, Sound};
+use crate::avatar::{AvatarStatus, AvatarTracker};
+use crate::chatbox::{self, Chatbox};
+use crate::mapping;
+use crate::melody::Practice;
+use crate::osc::{OscOptions, OscSender};
+use crate::osc_recording::OscRecorder;
+use crate::overlay::{self, Overlay, OverlayOptions, OverlayStatus};
+use crate::session::{Frame, SessionRecorder};
+use crate::smoothing::Smoother;
+use crate::sound_device::{Capturer, ChannelMode, Sound};
+use crate::transcribe::PitchRecorder;
 use crate::utils;
 
 pub const CHUNK_SIZE: usize = 1024;
-const BUFFER_SIZE: usize = CHUNK_SIZE * 4;
-const LPC_DEPTH: usize = 20;
+pub const FFT_SIZES: [usize; 3] = [CHUNK_SIZE * 2, CHUNK_SIZE * 4, CHUNK_SIZE * 8];
+const DEFAULT_FFT_SIZE: usize = CHUNK_SIZE * 4;
+const MAX_LPC_ORDER: usize = 32;
+pub const LPC_ORDERS: std::ops::RangeInclusive<usize> = 8..=MAX_LPC_ORDER;
+const DEFAULT_LPC_ORDER: usize = 20;
 const FORMANT_SPEC_SIZE: usize = 512;
+const FORMANT_HISTORY_LEN: usize = 50;
+pub const SPECTROGRAM_MAX_FREQ: f32 = 8192.0;
+pub const DEFAULT_HARMONICS: usize = 20;
+pub const MAX_HARMONICS: usize = 40;
+/// How long the analyzer waits for a chunk before checking for a lost device.
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
 
 struct Feature {
     rms: f32,
@@ -29,30 +49,69 @@ struct Feature {
 }
 
 struct FeatureAnalyzer {
-    detector: detector::mcleod::McLeodDetector<f32>,
+    size: usize,
+    lpc_order: usize,
+    pitch_algorithm: PitchAlgorithm,
+    detector: Box<dyn PitchDetector<f32>>,
     fft: Arc<dyn rustfft::Fft<f32>>,
+    window_sum: f32,
 }
 
 impl FeatureAnalyzer {
-    fn new() -> Self {
-        let detector = detector::mcleod::McLeodDetector::new(BUFFER_SIZE, BUFFER_SIZE / 2);
+    fn new(options: &AnalyzerOptions) -> Self {
+        let size = options.fft_size;
+        let detector: Box<dyn PitchDetector<f32>> = match options.pitch_algorithm {
+            PitchAlgorithm::McLeod => {
+                Box::new(detector::mcleod::McLeodDetector::new(size, size / 2))
+            }
+            PitchAlgorithm::Yin => Box::new(detector::yin::YINDetector::new(size, size / 2)),
+            PitchAlgorithm::Autocorrelation => Box::new(
+                detector::autocorrelation::AutocorrelationDetector::new(size, size / 2),
+            ),
+        };
         let mut planner = rustfft::FftPlanner::new();
-        let fft = planner.plan_fft_forward(BUFFER_SIZE);
-        Self { detector, fft }
+        let fft = planner.plan_fft_forward(size);
+        let window_sum = apodize::hanning_iter(size).sum::<f64>() as f32;
+        Self {
+            size,
+            lpc_order: options.lpc_order,
+            pitch_algorithm: options.pitch_algorithm,
+            detector,
+            fft,
+            window_sum,
+        }
     }
-    fn analyze(&mut self, sound: &Sound) -> Feature {
+
+    /// Whether this analyzer was built for the buffer size and algorithms in `options`.
+    fn matches(&self, options: &AnalyzerOptions) -> bool {
+        self.size == options.fft_size
+            && self.lpc_order == options.lpc_order
+            && self.pitch_algorithm == options.pitch_algorithm
+    }
+
+    fn analyze(&mut self, sound: &Sound, options: &AnalyzerOptions) -> Feature {
         let rms = calc_rms(sound);
-        let freq = self.analyze_freq(sound);
+        let gated = options.gate_enabled && utils::to_db(rms) < options.gate_db;
+        let freq = if gated {
+            None
+        } else {
+            self.analyze_freq(sound)
+        };
         let spectrum = self.analyze_spectrum(sound);
-        let gains: Vec<f32> = (1..=20)
+        let gains: Vec<f32> = (1..=options.harmonics)
             .map(|k| {
                 freq.map_or(0.0, |f0| {
                     let freq = f0 * k as f32;
-                    gain_at_freq(&spectrum, &freq).clamp(0.0, 1.0)
+                    let amplitude =
+                        self.harmonic_amplitude(&spectrum, freq, options.harmonic_method);
+                    options.gain_scale.normalize(amplitude)
                 })
             })
             .collect();
-        let (formant_spec, formant_peak) = self.analyze_formant(sound);
+        let (formant_spec, mut formant_peak) = self.analyze_formant(sound);
+        if gated {
+            formant_peak.clear();
+        }
 
         Feature {
             rms,
@@ -70,7 +129,7 @@ impl FeatureAnalyzer {
     }
 
     fn analyze_spectrum(&self, s: &Sound) -> Vec<(f32, f32)> {
-        let window = apodize::hanning_iter(BUFFER_SIZE);
+        let window = apodize::hanning_iter(self.size);
         let mut spec: Vec<Complex<f32>> = s
             .samples
             .iter()
@@ -78,17 +137,34 @@ impl FeatureAnalyzer {
             .map(|(a, b)| Complex::from(a * b as f32))
             .collect();
         self.fft.process(&mut spec);
-        let freq_step = s.samplerate as f32 / BUFFER_SIZE as f32;
+        let freq_step = s.samplerate as f32 / self.size as f32;
         spec.into_iter()
-            .take(BUFFER_SIZE / 2)
+            .take(self.size / 2)
             .enumerate()
             .map(|(i, c)| (i as f32 * freq_step, c.norm()))
             .collect()
     }
 
+    /// Estimates the amplitude of the sinusoid near `freq`, compensated for the window gain
+    /// so that a full-scale sine reads 1.0.
+    fn harmonic_amplitude(&self, spec: &[(f32, f32)], freq: f32, method: HarmonicMethod) -> f32 {
+        let freq_step = spec[1].0 - spec[0].0;
+        let (lower, upper) = match method {
+            HarmonicMethod::LocalPeak => (freq - freq_step, freq + freq_step),
+            HarmonicMethod::PeakPick => {
+                let half_semitone = 2.0f32.powf(1.0 / 24.0);
+                (freq / half_semitone, freq * half_semitone)
+            }
+        };
+        let lower = (lower / freq_step).round() as usize;
+        let upper = ((upper / freq_step).round() as usize).min(spec.len() - 1);
+        let peak = (lower..=upper).max_by(|&a, &b| spec[a].1.total_cmp(&spec[b].1));
+        peak.map_or(0.0, |i| interpolate_peak(spec, i) * 2.0 / self.window_sum)
+    }
+
     fn analyze_formant(&self, s: &Sound) -> (Vec<(f64, f64)>, Vec<f64>) {
         const CHUNK: usize = 2;
-        const RESAMPLED_BUFFER_SIZE: usize = BUFFER_SIZE / CHUNK;
+        let resampled_buffer_size = self.size / CHUNK;
         let nyquist = s.samplerate / 2;
         let resampled_rate = s.samplerate / CHUNK;
         let resampled_nyquist = nyquist as f64 / CHUNK as f64;
@@ -98,9 +174,11 @@ impl FeatureAnalyzer {
             .map(|chunk| chunk.iter().sum())
             .collect();
         process_hpf(&mut buffer, s.samplerate, 50.0);
-        process_window(&mut buffer, apodize::hanning_iter(RESAMPLED_BUFFER_SIZE));
+        process_window(&mut buffer, apodize::hanning_iter(resampled_buffer_size));
         let array = ndarray::Array::from_iter(buffer.iter().map(|&x| x as f64));
-        let filter_coeffs = calc_lpc_by_burg(array.view(), LPC_DEPTH).unwrap().to_vec();
+        let filter_coeffs = calc_lpc_by_burg(array.view(), self.lpc_order)
+            .unwrap()
+            .to_vec();
         let spec = calc_freq_responce(&filter_coeffs, FORMANT_SPEC_SIZE, resampled_rate);
         let roots: Vec<Complex<f64>> = calc_poly_roots(&filter_coeffs);
         let mut freqs: Vec<f64> = roots
@@ -120,36 +198,98 @@ struct ResultStore {
     gains: Vec<f32>,
     formant_spec: Vec<(f64, f64)>,
     formant_peak: Vec<f64>,
+    formant_history: VecDeque<Option<(f64, f64)>>,
+    samplerate: usize,
+    spectrum_history: VecDeque<Vec<f32>>,
+    device_error: Option<String>,
+    osc_error: Option<String>,
+    avatar: AvatarStatus,
+    overlay: OverlayStatus,
 }
 
 impl ResultStore {
-    fn new() -> Self {
+    fn new(harmonics: usize) -> Self {
         Self {
             rms: 0.0,
             freq_history: VecDeque::from([f32::NAN; 201]),
-            spectrum: vec![(0.0, 0.0); BUFFER_SIZE / 2],
-            gains: vec![0.0; 20],
+            spectrum: vec![],
+            gains: vec![0.0; harmonics],
             formant_spec: vec![(0.0, 0.0); FORMANT_SPEC_SIZE],
             formant_peak: vec![],
+            formant_history: VecDeque::from([None; FORMANT_HISTORY_LEN]),
+            samplerate: 0,
+            spectrum_history: VecDeque::new(),
+            device_error: None,
+            osc_error: None,
+            avatar: Default::default(),
+            overlay: Default::default(),
         }
     }
 
-    fn push(&mut self, f: &Feature) {
+    /// Clears the current values while no samples arrive, so the graphs do not keep showing
+    /// the last frame before the device was lost.
+    fn push_silence(&mut self) {
+        self.rms = 0.0;
+        self.freq_history.pop_front();
+        self.freq_history.push_back(f32::NAN);
+        self.gains.fill(0.0);
+        self.formant_peak.clear();
+        self.formant_history.pop_front();
+        self.formant_history.push_back(None);
+    }
+
+    fn push(&mut self, f: &Feature, samplerate: usize, history_seconds: f32) {
         self.rms = f.rms;
         self.freq_history.pop_front();
         self.freq_history.push_back(f.freq.unwrap_or(f32::NAN));
-        self.spectrum.copy_from_slice(&f.spectrum);
-        self.gains.copy_from_slice(&f.gains);
+        self.spectrum.clone_from(&f.spectrum);
+        self.gains.clone_from(&f.gains);
         self.formant_spec.copy_from_slice(&f.formant_spec);
         self.formant_peak.clone_from(&f.formant_peak);
+        self.formant_history.pop_front();
+        let f1_f2 = match f.formant_peak[..] {
+            [f1, f2, ..] if f.freq.is_some() => Some((f1, f2)),
+            _ => None,
+        };
+        self.formant_history.push_back(f1_f2);
+        let history_len = (history_seconds * samplerate as f32 / CHUNK_SIZE as f32) as usize;
+        let magnitudes: Vec<f32> = f
+            .spectrum
+            .iter()
+            .take_while(|(freq, _)| *freq <= SPECTROGRAM_MAX_FREQ)
+            .map(|(_, magnitude)| *magnitude)
+            .collect();
+        if self.samplerate != samplerate
+            || self
+                .spectrum_history
+                .back()
+                .is_some_and(|m| m.len() != magnitudes.len())
+        {
+            // The FFT size or the device has changed, so older frames have a different bin width
+            self.spectrum_history.clear();
+        }
+        self.samplerate = samplerate;
+        self.spectrum_history.push_back(magnitudes);
+        while self.spectrum_history.len() > history_len.max(1) {
+            self.spectrum_history.pop_front();
+        }
     }
 }
 
+/// Past spectra, oldest first, holding the magnitudes of the bins up to `SPECTROGRAM_MAX_FREQ`.
+pub struct SpectrumHistory {
+    pub frames: Vec<Vec<f32>>,
+    /// Seconds between frames.
+    pub frame_period: f32,
+    /// Hz between bins.
+    pub freq_step: f32,
+}
+
 pub struct Results(Arc<RwLock<ResultStore>>);
 
 impl Results {
-    fn new() -> Self {
-        Self(Arc::new(RwLock::new(ResultStore::new())))
+    fn new(harmonics: usize) -> Self {
+        Self(Arc::new(RwLock::new(ResultStore::new(harmonics))))
     }
 
     fn clone(&self) -> Self {
@@ -176,6 +316,12 @@ impl Results {
             .collect()
     }
 
+    /// The pitch of the latest frame, if one was detected.
+    pub fn freq(&self) -> Option<f32> {
+        let freq = *self.read().freq_history.back().unwrap();
+        (!freq.is_nan()).then_some(freq)
+    }
+
     pub fn spectrum(&self) -> Vec<(f32, f32)> {
         self.read().spectrum.clone()
     }
@@ -192,6 +338,21 @@ impl Results {
             .collect()
     }
 
+    /// Seconds between analyzed frames.
+    pub fn frame_period(&self) -> f32 {
+        CHUNK_SIZE as f32 / self.read().samplerate.max(1) as f32
+    }
+
+    pub fn spectrum_history(&self) -> SpectrumHistory {
+        let store = self.read();
+        let samplerate = store.samplerate.max(1) as f32;
+        SpectrumHistory {
+            frames: store.spectrum_history.iter().cloned().collect(),
+            frame_period: CHUNK_SIZE as f32 / samplerate,
+            freq_step: store.spectrum.get(1).map_or(1.0, |(freq, _)| *freq),
+        }
+    }
+
     pub fn gains(&self) -> Vec<f32> {
         self.read().gains.clone()
     }
@@ -203,64 +364,477 @@ impl Results {
     pub fn formant_peak(&self) -> Vec<f64> {
         self.read().formant_peak.clone()
     }
+
+    /// Why the input device is not delivering samples, if it has been lost.
+    pub fn device_error(&self) -> Option<String> {
+        self.read().device_error.clone()
+    }
+
+    /// Why OSC cannot be sent, such as a target the system refuses.
+    pub fn osc_error(&self) -> Option<String> {
+        self.read().osc_error.clone()
+    }
+
+    /// The avatar the analyzer sends to, while following it is enabled.
+    pub fn avatar(&self) -> AvatarStatus {
+        self.read().avatar.clone()
+    }
+
+    /// The overlay server, while the overlay is enabled.
+    pub fn overlay(&self) -> OverlayStatus {
+        self.read().overlay.clone()
+    }
+
+    /// (F1, F2) of recent frames, oldest first. `None` for frames without a detected pitch.
+    pub fn formant_history(&self) -> Vec<Option<(f64, f64)>> {
+        self.read().formant_history.iter().copied().collect()
+    }
+}
+
+/// Range of MIDI note numbers mapped onto the 0..1 pitch parameter sent over OSC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PitchRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl PitchRange {
+    /// E2..G5, the range printed on `E2G5_label.png` of the PitchMonitor prefab.
+    pub const E2_G5: Self = Self::new(40, 79);
+    pub const PRESETS: [Self; 4] = [
+        Self::E2_G5,
+        Self::new(28, 67),
+        Self::new(36, 84),
+        Self::new(55, 96),
+    ];
+
+    pub const fn new(low: u8, high: u8) -> Self {
+        Self { low, high }
+    }
+
+    pub fn normalize(&self, freq: f32) -> f32 {
+        self.normalize_note(freq_to_midi_note(&freq))
+    }
+
+    /// Ranges read from a file may be empty, which `normalize` cannot map onto 0..1.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.low >= self.high {
+            return Err(format!(
+                "the pitch range {}..{} is empty",
+                self.low, self.high
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn normalize_note(&self, midinote: f32) -> f32 {
+        if self.validate().is_err() {
+            return 0.0;
+        }
+        utils::normalize(midinote, self.low as f32, self.high as f32).clamp(0.0, 1.0)
+    }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+impl Default for PitchRange {
+    fn default() -> Self {
+        Self::E2_G5
+    }
+}
+
+/// Where the peak of each harmonic is searched for in the spectrum. In both cases the
+/// amplitude is refined by quadratic interpolation of the log magnitude around the peak bin.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HarmonicMethod {
+    /// Use the largest bin within ±1 bin of the harmonic frequency.
+    #[default]
+    #[serde(alias = "Interpolate")]
+    LocalPeak,
+    /// Use the largest bin within ±½ semitone of the harmonic frequency.
+    PeakPick,
+}
+
+/// How harmonic amplitudes are mapped onto the 0..1 `G*` parameters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GainScale {
+    /// Linear in dBFS, from `FLOOR_DB` (0.0) to `CEIL_DB` (1.0).
+    #[default]
+    Db,
+    /// Linear in amplitude, from silence (0.0) to `CEIL_DB` (1.0).
+    Linear,
+}
+
+impl GainScale {
+    pub const FLOOR_DB: f32 = -60.0;
+    pub const CEIL_DB: f32 = -15.0;
+
+    fn normalize(&self, amplitude: f32) -> f32 {
+        let v = match self {
+            Self::Db => utils::normalize(utils::to_db(amplitude), Self::FLOOR_DB, Self::CEIL_DB),
+            Self::Linear => amplitude / utils::from_db(Self::CEIL_DB),
+        };
+        v.clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PitchAlgorithm {
+    #[default]
+    McLeod,
+    Yin,
+    Autocorrelation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AnalyzerOptions {
     pub gain: f32,
+    pub channel_mode: ChannelMode,
+    pub pitch_algorithm: PitchAlgorithm,
+    /// Number of samples analyzed per frame, one of `FFT_SIZES`.
+    pub fft_size: usize,
+    pub lpc_order: usize,
+    /// Frames quieter than `gate_db` (RMS, dBFS) are treated as silence when enabled.
+    pub gate_enabled: bool,
+    pub gate_db: f32,
+    pub pitch_range: PitchRange,
+    pub send_pitch_range: bool,
+    pub harmonics: usize,
+    pub harmonic_method: HarmonicMethod,
+    pub gain_scale: GainScale,
+    /// Length of the spectrum history kept for the spectrogram view.
+    pub spectrogram_seconds: f32,
+}
+
+impl Default for AnalyzerOptions {
+    fn default() -> Self {
+        Self {
+            gain: 0.0,
+            channel_mode: Default::default(),
+            pitch_algorithm: Default::default(),
+            fft_size: DEFAULT_FFT_SIZE,
+            lpc_order: DEFAULT_LPC_ORDER,
+            gate_enabled: false,
+            gate_db: -50.0,
+            pitch_range: Default::default(),
+            send_pitch_range: false,
+            harmonics: DEFAULT_HARMONICS,
+            harmonic_method: Default::default(),
+            gain_scale: Default::default(),
+            spectrogram_seconds: 10.0,
+        }
+    }
+}
+
+impl AnalyzerOptions {
+    /// Checks options read from a file, as the analyzer panics on sizes it does not support.
+    pub fn validate(&self) -> Result<(), String> {
+        if !FFT_SIZES.contains(&self.fft_size) {
+            return Err(format!("unsupported FFT size: {}", self.fft_size));
+        }
+        if !LPC_ORDERS.contains(&self.lpc_order) {
+            return Err(format!("unsupported LPC order: {}", self.lpc_order));
+        }
+        if !(1..=MAX_HARMONICS).contains(&self.harmonics) {
+            return Err(format!(
+                "unsupported number of harmonics: {}",
+                self.harmonics
+            ));
+        }
+        self.pitch_range.validate()
+    }
+}
+
+/// What sends the features over OSC, kept across frames.
+struct OscOutput {
+    sender: OscSender,
+    smoother: Smoother,
+    chatbox: Chatbox,
+    avatar: AvatarTracker,
+    options: Arc<RwLock<OscOptions>>,
+}
+
+impl OscOutput {
+    fn new(options: Arc<RwLock<OscOptions>>) -> std::io::Result<Self> {
+        let sender = OscSender::new(&options.read().unwrap())?;
+        Ok(Self {
+            sender,
+            smoother: Default::default(),
+            chatbox: Default::default(),
+            avatar: Default::default(),
+            options,
+        })
+    }
+
+    /// Sends a frame, and the chatbox text when it is due.
+    fn send(
+        &mut self,
+        feature: Feature,
+        options: &AnalyzerOptions,
+        target_note: Option<u8>,
+        samplerate: usize,
+        results: &Results,
+        recorders: &mut Recorders,
+    ) {
+        let osc_options = self.options.read().unwrap().clone();
+        let mut osc_error = self.sender.configure(&osc_options).err();
+        if osc_options.chatbox.enabled {
+            let values = chatbox::Values {
+                freq: feature.freq,
+                formants: feature.formant_peak.clone(),
+                volume_db: utils::to_db(feature.rms),
+            };
+            let text = self
+                .chatbox
+                .update(&osc_options.chatbox, &values, Instant::now());
+            if let Some(text) = text {
+                let sent = self.sender.send_chatbox(&text, recorders.osc.as_mut());
+                osc_error = osc_error.or(sent.err());
+            }
+        }
+        let target_note = osc_options.send_target_note.then(|| {
+            target_note.map_or(-1.0, |key| options.pitch_range.normalize_note(key as f32))
+        });
+        let values = mapping::Values {
+            freq: feature.freq,
+            rms: feature.rms,
+            gains: feature.gains,
+            formants: feature.formant_peak,
+            pitch_range: options.pitch_range,
+            send_pitch_range: options.send_pitch_range,
+            target_note,
+        };
+        let mut avatar = self.avatar.update(&osc_options.avatar);
+        let mapping = osc_options
+            .avatar
+            .mapping(&osc_options.mapping, avatar.avatar.as_deref());
+        avatar.suspended = mapping.is_none();
+        results.write().avatar = avatar;
+        if let Some(mapping) = mapping {
+            let mut outputs = mapping.evaluate(&values);
+            self.smoother.apply(
+                &mut outputs,
+                &osc_options.smoothing,
+                CHUNK_SIZE as f32 / samplerate as f32,
+            );
+            let sent = self.sender.send(outputs, recorders.osc.as_mut());
+            osc_error = osc_error.or(sent.err());
+        }
+        results.write().osc_error = osc_error.map(|e| e.to_string());
+    }
+}
+
+/// What streams the features to the overlay page, kept across frames.
+struct OverlayOutput {
+    overlay: Overlay,
+    options: Arc<RwLock<OverlayOptions>>,
+}
+
+impl OverlayOutput {
+    fn send(&mut self, feature: &Feature, target_note: Option<u8>, results: &Results) {
+        let options = self.options.read().unwrap().clone();
+        results.write().overlay = self.overlay.update(&options);
+        if let Some(server) = self.overlay.server() {
+            let frame = overlay::Frame::new(
+                feature.freq,
+                feature.rms,
+                &feature.gains,
+                &feature.formant_peak,
+                options.vowels,
+                target_note,
+            );
+            server.broadcast(&frame);
+        }
+    }
+}
+
+/// Where the features are sent besides `Results`, independently of each other.
+struct Outputs {
+    osc: Option<OscOutput>,
+    overlay: Option<OverlayOutput>,
 }
 
 fn spawn_analyze_loop(
-    capturer: Capturer,
+    mut capturer: Capturer,
     results: Results,
     options: Arc<RwLock<AnalyzerOptions>>,
+    mut outputs: Outputs,
+    recorders: Arc<Mutex<Recorders>>,
+    capturers: mpsc::Receiver<Capturer>,
     stop: mpsc::Receiver<()>,
 ) {
     thread::spawn(move || {
-        let mut buffer = VecDeque::from([0.0; BUFFER_SIZE]);
-        let osc_sender = OscSender::new();
-        let mut feature_analyzer = FeatureAnalyzer::new();
+        let initial_options = *options.read().unwrap();
+        let mut buffer = VecDeque::from(vec![0.0; initial_options.fft_size]);
+        let mut feature_analyzer = FeatureAnalyzer::new(&initial_options);
+        let mut samplerate = 0;
         while stop.try_recv().is_err() {
-            let sound = capturer.rx.recv().unwrap();
+            // Dropping the previous capturer stops its capture thread
+            if let Some(new_capturer) = capturers.try_iter().last() {
+                capturer = new_capturer;
+            }
+            let received = capturer.rx.recv_timeout(RECV_TIMEOUT);
+            let device_error = capturer.error();
+            let lost = device_error.is_some();
+            results.write().device_error = device_error;
+            let chunk = match received {
+                Ok(chunk) => chunk,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if lost {
+                        results.write().push_silence();
+                    }
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    thread::sleep(RECV_TIMEOUT);
+                    continue;
+                }
+            };
+            let options = *options.read().unwrap();
+            let input = chunk.downmix(options.channel_mode);
+            if !feature_analyzer.matches(&options) || input.samplerate != samplerate {
+                feature_analyzer = FeatureAnalyzer::new(&options);
+                buffer = VecDeque::from(vec![0.0; options.fft_size]);
+                samplerate = input.samplerate;
+            }
             buffer.drain(..CHUNK_SIZE);
-            buffer.extend(sound.samples);
-            let factor = utils::from_db(options.read().unwrap().gain);
+            buffer.extend(&input.samples);
+            let factor = utils::from_db(options.gain);
             let sound = Sound {
-                samplerate: sound.samplerate,
+                samplerate: input.samplerate,
                 samples: buffer.iter().map(|s| s * factor).collect(),
             };
-            let feature = feature_analyzer.analyze(&sound);
-            results.write().push(&feature);
-            let freq_normalized = feature.freq.map_or(-1.0, normalize_freq);
-            let formants = feature
-                .formant_peak
-                .iter()
-                .take(4)
-                .map(|&f| f.clamp(0.0, 8192.0) as f32 / 0x3FFF as f32)
-                .collect();
-            osc_sender.send_param(freq_normalized, feature.gains, formants);
+            let feature = feature_analyzer.analyze(&sound, &options);
+            results
+                .write()
+                .push(&feature, sound.samplerate, options.spectrogram_seconds);
+            let target_note = recorders.lock().unwrap().record(&input, &feature, &options);
+            if let Some(overlay) = &mut outputs.overlay {
+                overlay.send(&feature, target_note, &results);
+            }
+            if let Some(osc) = &mut outputs.osc {
+                let mut recorders = recorders.lock().unwrap();
+                osc.send(
+                    feature,
+                    &options,
+                    target_note,
+                    sound.samplerate,
+                    &results,
+                    &mut recorders,
+                );
+            }
         }
     });
 }
 
 pub struct Analyzer {
     stop_sender: mpsc::Sender<()>,
+    capturer_sender: mpsc::Sender<Capturer>,
     pub results: Results,
     pub options: Arc<RwLock<AnalyzerOptions>>,
+    /// `None` for an analyzer that does not send OSC, such as the one for the comparison voice.
+    pub osc_options: Option<Arc<RwLock<OscOptions>>>,
+    /// `None` for an analyzer that does not stream to the overlay.
+    pub overlay_options: Option<Arc<RwLock<OverlayOptions>>>,
+    pub recorders: Arc<Mutex<Recorders>>,
+}
+
+/// What is fed with every analyzed frame while it is active, started and stopped from the GUI.
+#[derive(Default)]
+pub struct Recorders {
+    /// The reference melody being sung, scored as frames are analyzed.
+    pub practice: Option<Practice>,
+    pub pitch: Option<PitchRecorder>,
+    pub session: Option<SessionRecorder>,
+    /// Why the session stopped recording on its own.
+    pub session_error: Option<String>,
+    /// The OSC packets sent, for the analyzer that sends OSC.
+    pub osc: Option<OscRecorder>,
+}
+
+impl Recorders {
+    /// Returns the note of the melody to sing now.
+    fn record(
+        &mut self,
+        input: &Sound,
+        feature: &Feature,
+        options: &AnalyzerOptions,
+    ) -> Option<u8> {
+        if let Some(recorder) = &mut self.pitch {
+            recorder.push(feature.freq, CHUNK_SIZE as f64 / input.samplerate as f64);
+        }
+        if let Some(session) = &mut self.session {
+            let frame = Frame {
+                time: 0.0,
+                rms: feature.rms,
+                freq: feature.freq,
+                gains: feature.gains.clone(),
+                formants: feature.formant_peak.clone(),
+            };
+            if let Err(e) = session.push(input, options, frame) {
+                self.session_error = Some(e.to_string());
+                // Keeps what was recorded so far readable
+                if let Some(session) = self.session.take() {
+                    let _ = session.finish();
+                }
+            }
+        }
+        let practice = self.practice.as_mut()?;
+        practice.record(feature.freq);
+        practice.target().map(|note| note.key)
+    }
 }
 
 impl Analyzer {
-    pub fn new(capturer: Capturer, options: AnalyzerOptions) -> Self {
+    pub fn new(
+        capturer: Capturer,
+        options: AnalyzerOptions,
+        osc_options: Option<OscOptions>,
+        overlay_options: Option<OverlayOptions>,
+    ) -> Self {
         let (stop_sender, stop) = mpsc::channel();
-        let results = Results::new();
+        let (capturer_sender, capturers) = mpsc::channel();
+        let results = Results::new(options.harmonics);
         let options = Arc::new(RwLock::new(options));
-        spawn_analyze_loop(capturer, results.clone(), options.clone(), stop);
+        let osc_options = osc_options.map(|o| Arc::new(RwLock::new(o)));
+        let overlay_options = overlay_options.map(|o| Arc::new(RwLock::new(o)));
+        let osc = osc_options
+            .clone()
+            .and_then(|options| match OscOutput::new(options) {
+                Ok(osc) => Some(osc),
+                Err(e) => {
+                    results.write().osc_error = Some(e.to_string());
+                    None
+                }
+            });
+        let overlay = overlay_options.clone().map(|options| OverlayOutput {
+            overlay: Default::default(),
+            options,
+        });
+        let recorders = Arc::new(Mutex::new(Recorders::default()));
+        spawn_analyze_loop(
+            capturer,
+            results.clone(),
+            options.clone(),
+            Outputs { osc, overlay },
+            recorders.clone(),
+            capturers,
+            stop,
+        );
         Self {
             stop_sender,
+            capturer_sender,
             results,
             options,
+            osc_options,
+            overlay_options,
+            recorders,
         }
     }
+
+    /// Switches the input to `capturer` while keeping the analysis results.
+    pub fn set_capturer(&self, capturer: Capturer) {
+        self.capturer_sender.send(capturer).unwrap();
+    }
 }
 
 impl Drop for Analyzer {
@@ -269,36 +843,38 @@ impl Drop for Analyzer {
     }
 }
 
-fn freq_to_midi_note(freq: &f32) -> f32 {
+pub fn freq_to_midi_note(freq: &f32) -> f32 {
     if *freq < 1.0 {
         return 0.0;
     }
     69.0 + 12.0 * (freq / 440.0).log2()
 }
 
-fn normalize_freq(freq: f32) -> f32 {
-    const E2: f32 = 40.0;
-    const G5: f32 = 79.0;
-    let midinote = freq_to_midi_note(&freq);
-    utils::normalize(midinote, E2, G5).clamp(0.0, 1.0)
+/// Estimates the magnitude the peak at bin `i` would have if the sinusoid were centred on a
+/// bin. The fractional bin offset is found by fitting a parabola to the log magnitudes of the
+/// peak and its neighbours, and the Hann window's scalloping loss at that offset is undone.
+fn interpolate_peak(spec: &[(f32, f32)], i: usize) -> f32 {
+    let peak = spec[i].1;
+    if i == 0 || i + 1 >= spec.len() {
+        return peak;
+    }
+    let [a, b, c] = [spec[i - 1].1, peak, spec[i + 1].1].map(|m| m.max(f32::MIN_POSITIVE).ln());
+    let curvature = a - 2.0 * b + c;
+    if curvature >= 0.0 {
+        return peak;
+    }
+    let offset = (0.5 * (a - c) / curvature).clamp(-0.5, 0.5);
+    peak / hann_response(offset)
 }
 
-fn gain_at_freq(spec: &[(f32, f32)], freq: &f32) -> f32 {
-    let index = spec.iter().position(|(f, _)| *f >= *freq);
-    if index.is_none() {
-        return 0.0;
+/// Magnitude response of the Hann window `d` bins away from the centre of its main lobe,
+/// normalized to 1.0 at the centre.
+fn hann_response(d: f32) -> f32 {
+    if d.abs() < 1e-6 {
+        return 1.0;
     }
-    let index = index.unwrap();
-    let (upper_freq, upper_gain) = spec[index];
-    let (lower_freq, lower_gain) = if index >= 1 {
-        spec[index - 1]
-    } else {
-        (0.0, 0.0)
-    };
-    let freq_step = upper_freq - lower_freq;
-    let coeff = (freq % freq_step) / freq_step;
-    let power = utils::lerp(lower_gain, upper_gain, coeff);
-    power.ln() * 0.2
+    let x = f32::consts::PI * d;
+    x.sin() / x / (1.0 - d * d)
 }
 
 fn calc_rms(s: &Sound) -> f32 {
@@ -327,7 +903,9 @@ fn calc_freq_responce(coeffs: &[f64], size: usize, samplerate: usize) -> Vec<(f6
 }
 
 fn calc_poly_roots(coeffs: &Vec<f64>) -> Vec<Complex<f64>> {
-    let mut poly = [1.0; LPC_DEPTH + 1];
+    // Orders below the maximum are padded with zeros, which only adds roots at the origin.
+    let mut poly = [0.0; MAX_LPC_ORDER + 1];
+    poly[0] = 1.0;
     poly.iter_mut()
         .skip(1)
         .zip(coeffs)
@@ -353,3 +931,94 @@ fn process_hpf(s: &mut [f32], samplerate: usize, cutoff_freq: f32) {
 fn process_window<I: Iterator<Item = f64>>(s: &mut [f32], window: I) {
     s.iter_mut().zip(window).for_each(|(x, w)| *x *= w as f32);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLERATE: usize = 48000;
+
+    fn harmonic_sound(f0: f32, amplitudes: &[f32]) -> Sound {
+        let samples = (0..DEFAULT_FFT_SIZE)
+            .map(|n| {
+                let t = n as f32 / SAMPLERATE as f32;
+                amplitudes
+                    .iter()
+                    .enumerate()
+                    .map(|(k, a)| a * (2.0 * f32::consts::PI * f0 * (k + 1) as f32 * t).sin())
+                    .sum()
+            })
+            .collect();
+        Sound {
+            samples,
+            samplerate: SAMPLERATE,
+        }
+    }
+
+    #[test]
+    fn test_harmonic_amplitude() {
+        let analyzer = FeatureAnalyzer::new(&Default::default());
+        let amplitudes = [0.5, 0.25, 0.1, 0.05, 0.01];
+        // 207.3 Hz and its harmonics fall between bins (bin width is 11.72 Hz)
+        let f0 = 207.3;
+        let spectrum = analyzer.analyze_spectrum(&harmonic_sound(f0, &amplitudes));
+        for method in [HarmonicMethod::LocalPeak, HarmonicMethod::PeakPick] {
+            for (k, a) in amplitudes.iter().enumerate() {
+                let freq = f0 * (k + 1) as f32;
+                let amplitude = analyzer.harmonic_amplitude(&spectrum, freq, method);
+                let error_db = utils::to_db(amplitude) - utils::to_db(*a);
+                assert!(error_db.abs() < 0.1, "{method:?} G{}: {error_db} dB", k + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_harmonic_amplitude_between_bins() {
+        let analyzer = FeatureAnalyzer::new(&Default::default());
+        let freq_step = SAMPLERATE as f32 / DEFAULT_FFT_SIZE as f32;
+        let f0 = 20.5 * freq_step;
+        let spectrum = analyzer.analyze_spectrum(&harmonic_sound(f0, &[0.5]));
+        let nearest_bin = spectrum[20].1 * 2.0 / analyzer.window_sum;
+        let interpolated = analyzer.harmonic_amplitude(&spectrum, f0, HarmonicMethod::LocalPeak);
+        // Hann scalloping loss is about 1.4 dB half way between bins
+        assert!(utils::to_db(nearest_bin / 0.5) < -1.0);
+        assert!(utils::to_db(interpolated / 0.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_harmonic_method_alias() {
+        // Settings and profiles saved before the rename
+        let method: HarmonicMethod = serde_json::from_str(r#""Interpolate""#).unwrap();
+        assert_eq!(method, HarmonicMethod::LocalPeak);
+        let json = serde_json::to_string(&HarmonicMethod::LocalPeak).unwrap();
+        assert_eq!(json, r#""LocalPeak""#);
+    }
+
+    #[test]
+    fn test_gains() {
+        let mut analyzer = FeatureAnalyzer::new(&Default::default());
+        let amplitudes = [0.1, 0.05, 0.01];
+        let feature = analyzer.analyze(&harmonic_sound(220.0, &amplitudes), &Default::default());
+        assert!((feature.freq.unwrap() - 220.0).abs() < 1.0);
+        for (gain, a) in feature.gains.iter().zip(amplitudes) {
+            let expected = GainScale::Db.normalize(a);
+            assert!((gain - expected).abs() < 0.01, "{gain} != {expected}");
+        }
+        assert!(feature.gains[amplitudes.len()..].iter().all(|&g| g < 0.05));
+    }
+
+    #[test]
+    fn test_pitch_range() {
+        let range = PitchRange::E2_G5;
+        assert_eq!(range.normalize_note(40.0), 0.0);
+        assert_eq!(range.normalize_note(79.0), 1.0);
+        for empty in [
+            PitchRange::new(60, 60),
+            PitchRange::new(0, 0),
+            PitchRange::new(70, 60),
+        ] {
+            assert!(empty.validate().is_err());
+            assert_eq!(empty.normalize_note(65.0), 0.0);
+        }
+    }
+}
@@ -1,34 +1,83 @@
 use std::io::Read;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use utils::Elipsis;
 
 use crate::{
-    analyzer::{Analyzer, AnalyzerOptions, CHUNK_SIZE},
-    sound_device::DeviceList,
+    analyzer::{
+        freq_to_midi_note, Analyzer, AnalyzerOptions, GainScale, HarmonicMethod, PitchAlgorithm,
+        PitchRange, CHUNK_SIZE, FFT_SIZES, LPC_ORDERS, MAX_HARMONICS,
+    },
+    chatbox::{ChatboxOptions, Template, DEFAULT_TEMPLATE, INTERVALS},
+    cli::Args,
+    compare::{CompareSource, Difference},
+    mapping::Mapping,
+    melody::{Melody, Practice},
+    osc::{OscOptions, DEFAULT_MAX_RATE, MAX_PAYLOADS, MAX_RATES},
+    osc_recording::{OscRecorder, OscReplayer},
+    profile::Profile,
+    session::{Replay, Session, SessionRecorder},
+    settings::{Layout, Settings},
+    smoothing::{Smoothing, SmoothingOptions},
+    sound_device::{ChannelMode, Device, DeviceList},
+    spectrogram::{Colormap, FreqScale, SpectrogramOptions, SpectrogramView},
+    transcribe::PitchRecorder,
     utils,
+    vowel::VowelSet,
 };
 use eframe::egui;
-use egui_plot::{Bar, BarChart, Line, Plot, PlotPoints};
+use egui_plot::{
+    Bar, BarChart, HLine, Line, LineStyle, Plot, PlotImage, PlotPoint, PlotPoints, Points, Polygon,
+    Text,
+};
+
+/// Color of the comparison voice in the graphs.
+const COMPARISON_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 128, 255);
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+/// WASAPI has no device change notifications in the version used, so the list is polled.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const REPLAY_SPEEDS: [f32; 5] = [0.25, 0.5, 1.0, 1.5, 2.0];
 
 pub struct App {
     device_list: DeviceList,
+    devices_polled_at: Instant,
     analyzer: Option<Analyzer>,
-    analyzer_options: AnalyzerOptions,
-    force_show_graph: bool,
-}
-
-impl Default for App {
-    fn default() -> Self {
-        Self {
-            device_list: DeviceList::new(),
-            analyzer: None,
-            analyzer_options: Default::default(),
-            force_show_graph: false,
-        }
-    }
+    comparison: Option<Analyzer>,
+    compare_message: Option<String>,
+    melody: Option<Arc<Melody>>,
+    melody_message: Option<String>,
+    midi_message: Option<String>,
+    /// The WAV file of the last session that was recorded.
+    session_saved: Option<String>,
+    session_message: Option<String>,
+    osc_replayer: Option<OscReplayer>,
+    /// Where a recording of OSC packets is sent, which may differ from the live target.
+    osc_replay_target: SocketAddrV4,
+    osc_recording_message: Option<String>,
+    mapping_message: Option<String>,
+    /// The avatar whose profile was applied last.
+    avatar_id: Option<String>,
+    /// A recorded session shown instead of the live input while it is open.
+    replay: Option<Replay>,
+    replay_sends_osc: bool,
+    settings: Settings,
+    saved_settings: Settings,
+    saved_at: Instant,
+    /// Off while a settings file that could not be loaded is still in place, so that it is not
+    /// overwritten.
+    saves_settings: bool,
+    /// Why the settings could not be loaded, and where the file was moved to.
+    settings_load_message: Option<String>,
+    settings_save_message: Option<String>,
+    spectrogram: SpectrogramView,
+    profile_name: String,
+    profile_message: Option<String>,
 }
 
 impl App {
-    pub fn new(cc: &eframe::CreationContext) -> Self {
+    pub fn new(cc: &eframe::CreationContext, args: Args) -> Self {
         let mut fonts = egui::FontDefinitions::default();
         let mut fontfile = std::fs::File::open("C:/Windows/Fonts/Meiryo.ttc").unwrap();
         let mut fontdata = Vec::new();
@@ -43,21 +92,799 @@ impl App {
         cc.egui_ctx.set_fonts(fonts);
         cc.egui_ctx.set_theme(egui::Theme::Dark);
         egui_extras::install_image_loaders(&cc.egui_ctx);
-        Default::default()
+
+        let mut saves_settings = true;
+        let mut settings_load_message = None;
+        let mut settings = Settings::load().unwrap_or_else(|e| {
+            let message = match Settings::back_up() {
+                Ok(backup) => format!(
+                    "設定を読み込めないため、既定の設定を使います（元の設定は {} に移動しました）: {e}",
+                    backup.display()
+                ),
+                Err(_) => {
+                    saves_settings = false;
+                    format!("設定を読み込めないため、既定の設定を使います（元の設定を残すため、設定は保存されません）: {e}")
+                }
+            };
+            settings_load_message = Some(message);
+            Default::default()
+        });
+        let mut profile_message = None;
+        if let Some(profile) = &args.profile {
+            let path = Path::new(profile);
+            let name = if path.is_file() {
+                match Profile::import(path) {
+                    Ok(imported) => {
+                        let name = imported.name.clone();
+                        settings.add_profile(imported);
+                        name
+                    }
+                    Err(e) => {
+                        profile_message = Some(format!("読み込みに失敗しました: {e}"));
+                        profile.clone()
+                    }
+                }
+            } else {
+                profile.clone()
+            };
+            if !settings.apply_profile(&name) && profile_message.is_none() {
+                profile_message = Some(format!("プロファイル {name} が見つかりません"));
+            }
+        }
+        let mut melody_message = None;
+        let melody = settings.ui.melody_path.as_deref().and_then(|path| {
+            Melody::load(path)
+                .inspect_err(|e| melody_message = Some(format!("読み込みに失敗しました: {e}")))
+                .ok()
+                .map(Arc::new)
+        });
+        let mut device_list = DeviceList::new();
+        if let (false, Some(device_id)) = (settings.follow_default_device, &settings.device_id) {
+            device_list.select(device_id);
+        }
+        Self {
+            device_list,
+            devices_polled_at: Instant::now(),
+            analyzer: None,
+            comparison: None,
+            compare_message: None,
+            melody,
+            melody_message,
+            midi_message: None,
+            session_saved: None,
+            session_message: None,
+            osc_replayer: None,
+            osc_replay_target: settings.osc.target,
+            osc_recording_message: None,
+            mapping_message: None,
+            avatar_id: None,
+            replay: None,
+            replay_sends_osc: false,
+            saved_settings: settings.clone(),
+            settings,
+            saved_at: Instant::now(),
+            saves_settings,
+            settings_load_message,
+            settings_save_message: None,
+            spectrogram: Default::default(),
+            profile_name: String::new(),
+            profile_message,
+        }
     }
 
     fn is_running(&self) -> bool {
         self.analyzer.is_some()
     }
 
+    /// Whether the live analyzer is recording the pitch, the session or the OSC packets, which
+    /// stopping it would lose.
+    fn is_recording(&self) -> bool {
+        self.analyzer.as_ref().is_some_and(|analyzer| {
+            let recorders = analyzer.recorders.lock().unwrap();
+            recorders.pitch.is_some() || recorders.session.is_some() || recorders.osc.is_some()
+        })
+    }
+
     fn start(&mut self) {
-        let capturer = self.device_list.device().capturer(CHUNK_SIZE);
-        let analyzer = Analyzer::new(capturer, self.analyzer_options);
+        let Some(device) = self.device_list.device() else {
+            return;
+        };
+        self.replay = None;
+        let capturer = device.capturer(CHUNK_SIZE);
+        let osc_options = Some(self.settings.osc.clone());
+        let overlay_options = Some(self.settings.overlay.clone());
+        let analyzer = Analyzer::new(
+            capturer,
+            self.settings.analyzer,
+            osc_options,
+            overlay_options,
+        );
         self.analyzer = analyzer.into();
+        self.start_comparison();
     }
 
     fn stop(&mut self) {
+        self.replay.take();
         self.analyzer.take();
+        self.comparison.take();
+    }
+
+    /// (Re)starts the analyzer for the comparison voice if the comparison mode is enabled.
+    fn start_comparison(&mut self) {
+        self.comparison = None;
+        self.compare_message = None;
+        let compare = &self.settings.compare;
+        let source = compare.source.as_ref().filter(|_| compare.enabled);
+        let (true, Some(source)) = (self.is_running(), source) else {
+            return;
+        };
+        match source.start(&self.device_list, self.settings.analyzer) {
+            Ok(analyzer) => self.comparison = Some(analyzer),
+            Err(e) => self.compare_message = Some(format!("比較の入力を開始できません: {e}")),
+        }
+    }
+
+    fn apply_options(&self) {
+        if let Some(analyzer) = &self.analyzer {
+            *analyzer.options.write().unwrap() = self.settings.analyzer;
+        }
+        // A replay keeps the analyzer options it was recorded with
+        if let Some(analyzer) = self.osc_analyzer() {
+            if let Some(osc_options) = &analyzer.osc_options {
+                osc_options.write().unwrap().clone_from(&self.settings.osc);
+            }
+            if let Some(overlay_options) = &analyzer.overlay_options {
+                overlay_options
+                    .write()
+                    .unwrap()
+                    .clone_from(&self.settings.overlay);
+            }
+        }
+        if let Some(comparison) = &self.comparison {
+            let mut options = comparison.options.write().unwrap();
+            *options = AnalyzerOptions {
+                channel_mode: options.channel_mode,
+                ..self.settings.analyzer
+            };
+        }
+    }
+
+    /// Switches to the selected device, without interrupting the analysis if it is running.
+    fn change_device(&mut self) {
+        let Some(device) = self.device_list.device() else {
+            return;
+        };
+        self.settings.device_id = Some(device.id.clone());
+        if let Some(analyzer) = &self.analyzer {
+            analyzer.set_capturer(device.capturer(CHUNK_SIZE));
+        }
+    }
+
+    /// Picks up added and removed devices, and switches to the new system default device if
+    /// the user follows it.
+    fn refresh_devices(&mut self) {
+        self.device_list.refresh();
+        self.devices_polled_at = Instant::now();
+        if !self.settings.follow_default_device {
+            return;
+        }
+        let Some(default_id) = self.device_list.default_id().map(str::to_owned) else {
+            return;
+        };
+        let selected_id = self.device_list.device().map(|device| &device.id);
+        if selected_id != Some(&default_id) && self.device_list.select(&default_id) {
+            self.change_device();
+        }
+    }
+
+    fn compare_ui(&mut self, ui: &mut egui::Ui) {
+        let compare = &mut self.settings.compare;
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            changed |= ui
+                .checkbox(&mut compare.enabled, "比較")
+                .on_hover_text("2 つ目の声を重ねて表示し、差を表示する")
+                .changed();
+            let selected_text = match &compare.source {
+                Some(source) => compare_source_to_str(source, &self.device_list),
+                None => "-".to_owned(),
+            };
+            egui::ComboBox::from_id_salt("compare_source")
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    for device in &self.device_list.devices {
+                        let channel_mode = match &compare.source {
+                            Some(CompareSource::Device { channel_mode, .. }) => *channel_mode,
+                            _ => ChannelMode::Sum,
+                        };
+                        let source = CompareSource::Device {
+                            id: device.id.clone(),
+                            channel_mode,
+                        };
+                        let selected = matches!(
+                            &compare.source,
+                            Some(CompareSource::Device { id, .. }) if *id == device.id
+                        );
+                        if ui
+                            .selectable_label(selected, device_label(device))
+                            .clicked()
+                        {
+                            compare.source = Some(source);
+                            changed = true;
+                        }
+                    }
+                });
+            if let Some(CompareSource::Device { id, channel_mode }) = &mut compare.source {
+                let device = self.device_list.devices.iter().find(|d| d.id == *id);
+                let channels = device.map_or(1, |d| d.channels);
+                changed |= channel_ui(ui, channel_mode, channels);
+            }
+            if ui.button("ファイル...").clicked() {
+                let file = rfd::FileDialog::new()
+                    .add_filter("WAV", &["wav"])
+                    .pick_file();
+                if let Some(path) = file {
+                    compare.source = Some(CompareSource::File(path));
+                    changed = true;
+                }
+            }
+        });
+        if changed {
+            self.start_comparison();
+        }
+        if let Some(message) = &self.compare_message {
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+    }
+
+    fn melody_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("メロディ");
+            let name = self
+                .settings
+                .ui
+                .melody_path
+                .as_ref()
+                .filter(|_| self.melody.is_some())
+                .and_then(|path| path.file_name())
+                .map_or_else(
+                    || "-".to_owned(),
+                    |name| name.to_string_lossy().into_owned(),
+                );
+            ui.label(name);
+            if ui.button("読込").clicked() {
+                let file = rfd::FileDialog::new()
+                    .add_filter("MIDI / MusicXML", &["mid", "midi", "musicxml", "xml"])
+                    .pick_file();
+                if let Some(path) = file {
+                    match Melody::load(&path) {
+                        Ok(melody) => {
+                            self.melody = Some(Arc::new(melody));
+                            self.settings.ui.melody_path = Some(path);
+                            self.melody_message = None;
+                        }
+                        Err(e) => {
+                            self.melody_message = Some(format!("読み込みに失敗しました: {e}"));
+                        }
+                    }
+                }
+            }
+            let melody = self.melody.clone().filter(|_| self.is_running());
+            let start = ui
+                .add_enabled(melody.is_some(), egui::Button::new("開始"))
+                .on_hover_text("メロディを最初から開始し、採点する");
+            if let (true, Some(melody), Some(analyzer)) = (start.clicked(), melody, &self.analyzer)
+            {
+                analyzer.recorders.lock().unwrap().practice = Some(Practice::new(melody));
+            }
+            if ui.button("クリア").clicked() {
+                self.melody = None;
+                self.settings.ui.melody_path = None;
+                if let Some(analyzer) = &self.analyzer {
+                    analyzer.recorders.lock().unwrap().practice.take();
+                }
+            }
+            let send_target_note = &mut self.settings.osc.send_target_note;
+            let resp = ui
+                .checkbox(send_target_note, "OSC")
+                .on_hover_text("目標の音を FT_Target として送信する");
+            if resp.changed() {
+                self.apply_options();
+            }
+        });
+        if let Some(message) = &self.melody_message {
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+    }
+
+    /// Records the pitch while running and saves it as a Standard MIDI File when stopped.
+    fn pitch_recorder_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("MIDI");
+            let Some(analyzer) = &self.analyzer else {
+                ui.add_enabled(false, egui::Button::new("録音"));
+                return;
+            };
+            let mut recorders = analyzer.recorders.lock().unwrap();
+            match &recorders.pitch {
+                None => {
+                    if ui
+                        .button("録音")
+                        .on_hover_text("ピッチをノートに変換して記録する")
+                        .clicked()
+                    {
+                        recorders.pitch = Some(PitchRecorder::new());
+                        self.midi_message = None;
+                    }
+                }
+                Some(recorder) => {
+                    let stop = ui.button("停止して保存").clicked();
+                    ui.label(format!("{:.0}s", recorder.seconds()));
+                    if stop {
+                        let recorder = recorders.pitch.take().unwrap();
+                        // Do not block the analyzer while the dialog is open
+                        drop(recorders);
+                        let file = rfd::FileDialog::new()
+                            .add_filter("MIDI", &["mid"])
+                            .set_file_name("melody.mid")
+                            .save_file();
+                        if let Some(path) = file {
+                            self.midi_message = recorder
+                                .save(&path)
+                                .err()
+                                .map(|e| format!("書き出しに失敗しました: {e}"));
+                        }
+                    }
+                }
+            }
+        });
+        if let Some(message) = &self.midi_message {
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+    }
+
+    /// Records the OSC packets sent, and sends a recording again with its original timing.
+    fn osc_recording_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("OSC 記録");
+            match &self.analyzer {
+                Some(analyzer) => {
+                    osc_recorder_ui(ui, analyzer, &mut self.osc_recording_message);
+                }
+                None => {
+                    ui.add_enabled(false, egui::Button::new("記録..."));
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("OSC 再送");
+            let replayer = self.osc_replayer.as_ref().filter(|r| !r.is_finished());
+            if let Some(replayer) = replayer {
+                let stop = ui.button("停止").clicked();
+                ui.label(format!(
+                    "{:.1} / {:.1}s",
+                    replayer.seconds(),
+                    replayer.duration
+                ));
+                ui.ctx().request_repaint_after(Duration::from_millis(100));
+                if stop {
+                    self.osc_replayer = None;
+                }
+                return;
+            }
+            let replay = ui
+                .button("再生...")
+                .on_hover_text("記録した OSC を元のタイミングで送信先に送る");
+            address_ui(ui, "osc_replay_target", &mut self.osc_replay_target);
+            if !replay.clicked() {
+                return;
+            }
+            let file = rfd::FileDialog::new()
+                .add_filter("OSC", &["jsonl"])
+                .pick_file();
+            if let Some(path) = file {
+                self.osc_recording_message = None;
+                match OscReplayer::start(&path, self.osc_replay_target) {
+                    Ok(replayer) => self.osc_replayer = Some(replayer),
+                    Err(e) => {
+                        self.osc_recording_message = Some(format!("読み込めません: {e}"));
+                    }
+                }
+            }
+        });
+        if let Some(message) = &self.osc_recording_message {
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+    }
+
+    /// Records the input and the features of every frame, for reporting what the analyzer saw,
+    /// and opens recorded sessions for replay.
+    fn session_ui(&mut self, ui: &mut egui::Ui) {
+        let mut replay_file = None;
+        let recording = self.is_recording();
+        ui.horizontal(|ui| {
+            ui.label("セッション");
+            if let Some(analyzer) = &self.analyzer {
+                let saved = &mut self.session_saved;
+                session_recorder_ui(ui, analyzer, saved, &mut self.session_message);
+            } else {
+                ui.add_enabled(false, egui::Button::new("記録"));
+            }
+            // Replaying stops the live analysis, which would drop the recordings unsaved
+            if ui
+                .add_enabled(!recording, egui::Button::new("再生..."))
+                .on_hover_text("記録したセッションを再生する")
+                .on_disabled_hover_text("記録中は再生できません。記録を停止してください")
+                .clicked()
+            {
+                let mut dialog = rfd::FileDialog::new().add_filter("セッション", &["jsonl"]);
+                if let Some(dir) = Settings::sessions_dir().filter(|dir| dir.exists()) {
+                    dialog = dialog.set_directory(dir);
+                }
+                replay_file = dialog.pick_file();
+            }
+        });
+        if let Some(path) = replay_file {
+            self.session_saved = None;
+            self.session_message = None;
+            match Session::load(&path) {
+                Ok(session) => self.open_replay(Arc::new(session), 0),
+                Err(e) => self.session_message = Some(format!("読み込めません: {e}")),
+            }
+        }
+        if let Some(path) = &self.session_saved {
+            ui.label(format!("保存しました: {path}"));
+        }
+        if let Some(message) = &self.session_message {
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+    }
+
+    /// The analyzer that sends OSC, live or replaying.
+    fn osc_analyzer(&self) -> Option<&Analyzer> {
+        let replay = self.replay.as_ref().map(|replay| &replay.analyzer);
+        self.analyzer.as_ref().or(replay)
+    }
+
+    /// Applies the profile assigned to the avatar when VRChat switches to another one.
+    fn follow_avatar(&mut self) {
+        let status = self
+            .osc_analyzer()
+            .map(|analyzer| analyzer.results.avatar());
+        let Some(avatar) = status.and_then(|status| status.avatar) else {
+            // Also after the analyzer is stopped, so that the profile of the avatar is applied
+            // again when the next analyzer hears of it
+            self.avatar_id = None;
+            return;
+        };
+        if self.avatar_id.as_deref() == Some(&avatar.id) {
+            return;
+        }
+        self.avatar_id = Some(avatar.id.clone());
+        let profile = self.settings.osc.avatar.profiles.get(&avatar.id).cloned();
+        if let Some(name) = profile {
+            if self.settings.apply_profile(&name) {
+                self.profile_name = name;
+                self.apply_options();
+            }
+        }
+    }
+
+    /// The page for OBS browser sources and its WebSocket server.
+    fn overlay_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let options = &mut self.settings.overlay;
+        ui.horizontal(|ui| {
+            changed |= ui
+                .checkbox(&mut options.enabled, "オーバーレイ")
+                .on_hover_text("OBS のブラウザソース用のページと、フレームごとの特徴量 (JSON) を WebSocket で配信する")
+                .changed();
+            ui.add_enabled_ui(options.enabled, |ui| {
+                ui.label("ポート");
+                let port = egui::DragValue::new(&mut options.port).range(1..=65535);
+                changed |= ui.add(port).changed();
+                ui.label("母音");
+                egui::ComboBox::from_id_salt("overlay_vowels")
+                    .selected_text(options.vowels.name())
+                    .show_ui(ui, |ui| {
+                        for set in VowelSet::ALL {
+                            changed |= ui
+                                .selectable_value(&mut options.vowels, set, set.name())
+                                .changed();
+                        }
+                    });
+            });
+        });
+        if !options.enabled {
+            return changed;
+        }
+        let status = self
+            .osc_analyzer()
+            .map(|analyzer| analyzer.results.overlay())
+            .unwrap_or_default();
+        if let Some(error) = &status.error {
+            let message = format!("配信できません: {error}");
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+            return changed;
+        }
+        let Some(port) = status.port else {
+            return changed;
+        };
+        ui.horizontal(|ui| {
+            ui.hyperlink(format!("http://localhost:{port}/"));
+            ui.label(format!("接続数 {}", status.clients));
+        });
+        changed
+    }
+
+    /// Following the avatar, and the mapping and profile assigned to the current one.
+    fn avatar_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let options = &mut self.settings.osc.avatar;
+        ui.horizontal(|ui| {
+            changed |= ui
+                .checkbox(&mut options.enabled, "アバター追従")
+                .on_hover_text(
+                    "VRChat のアバター変更を受信して、アバターに割り当てたマッピングとプロファイルに切り替える",
+                )
+                .changed();
+            ui.label("受信ポート");
+            let port = egui::DragValue::new(&mut options.listen_port).range(1..=65535);
+            changed |= ui.add_enabled(options.enabled, port).changed();
+        });
+        if !options.enabled {
+            return changed;
+        }
+        let status = self
+            .osc_analyzer()
+            .map(|analyzer| analyzer.results.avatar())
+            .unwrap_or_default();
+        if let Some(error) = &status.error {
+            let message = format!("受信できません: {error}");
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+        let Some(avatar) = status.avatar else {
+            ui.label("アバター: 未受信");
+            return changed;
+        };
+        let options = &mut self.settings.osc.avatar;
+        ui.horizontal(|ui| {
+            ui.label(format!("アバター: {}", avatar.name()))
+                .on_hover_text(&avatar.id);
+            if status.suspended {
+                ui.colored_label(
+                    egui::Color32::LIGHT_RED,
+                    "VoiceAnalyzer のパラメーターがないため送信を停止中",
+                );
+            }
+        });
+        if let Some(error) = &avatar.config_error {
+            let message = format!("OSC の設定ファイルを読み込めません: {error}");
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+        ui.horizontal(|ui| {
+            if options.mappings.contains_key(&avatar.id) {
+                if ui.button("マッピングの割り当てを解除").clicked() {
+                    options.mappings.remove(&avatar.id);
+                    changed = true;
+                }
+            } else if ui
+                .button("マッピングを割り当て")
+                .on_hover_text("現在のマッピングをこのアバターで使う")
+                .clicked()
+            {
+                let mapping = self.settings.osc.mapping.clone();
+                options.mappings.insert(avatar.id.clone(), mapping);
+                changed = true;
+            }
+            match options.profiles.get(&avatar.id) {
+                Some(name) => {
+                    let label = format!("プロファイル {name} の割り当てを解除");
+                    if ui.button(label).clicked() {
+                        options.profiles.remove(&avatar.id);
+                    }
+                }
+                None => {
+                    let active = self.settings.active_profile.clone();
+                    let button = egui::Button::new("プロファイルを割り当て");
+                    if ui
+                        .add_enabled(active.is_some(), button)
+                        .on_hover_text("選択中のプロファイルをこのアバターで使う")
+                        .clicked()
+                    {
+                        options.profiles.insert(avatar.id.clone(), active.unwrap());
+                    }
+                }
+            }
+        });
+        changed
+    }
+
+    /// Replaces the live analysis with a replay of `session` from `chunk`.
+    fn open_replay(&mut self, session: Arc<Session>, chunk: usize) {
+        self.stop();
+        let osc_options = self.replay_sends_osc.then(|| self.settings.osc.clone());
+        let overlay_options = Some(self.settings.overlay.clone());
+        let replay = Replay::new(session, osc_options, overlay_options);
+        if chunk > 0 {
+            replay.seek(chunk);
+        }
+        self.replay = Some(replay);
+    }
+
+    /// The controls of the replay: play and pause, frame step, speed and the timeline.
+    fn replay_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(replay) = &self.replay else {
+            return;
+        };
+        let mut close = false;
+        let mut osc_changed = false;
+        ui.horizontal(|ui| {
+            ui.label(format!("再生: {}", replay.session.id));
+            let chunks = replay.session.chunks();
+            let mut playback = replay.playback.lock().unwrap();
+            let (icon, hover) = if playback.playing {
+                ("⏸", "一時停止")
+            } else {
+                ("▶", "再生")
+            };
+            if ui.button(icon).on_hover_text(hover).clicked() {
+                // Playing again at the end starts over
+                if !playback.playing && playback.position >= chunks {
+                    playback.position = 0;
+                }
+                playback.playing = !playback.playing;
+            }
+            let step = ui
+                .add_enabled(!playback.playing, egui::Button::new("⏭"))
+                .on_hover_text("1 フレーム進める")
+                .clicked();
+            egui::ComboBox::from_id_salt("replay_speed")
+                .selected_text(format!("×{}", playback.speed))
+                .width(60.0)
+                .show_ui(ui, |ui| {
+                    for speed in REPLAY_SPEEDS {
+                        ui.selectable_value(&mut playback.speed, speed, format!("×{speed}"));
+                    }
+                });
+            drop(playback);
+            if step {
+                replay.step();
+            }
+            let session = &replay.session;
+            let mut current = replay.current();
+            let slider = egui::Slider::new(&mut current, 0..=chunks.saturating_sub(1))
+                .custom_formatter(|chunk, _| format!("{:.2}s", session.seconds_at(chunk as usize)))
+                .custom_parser(|text| {
+                    let seconds: f64 = text.trim_end_matches('s').parse().ok()?;
+                    Some(seconds * session.samplerate as f64 / CHUNK_SIZE as f64)
+                });
+            if ui.add(slider).changed() {
+                replay.seek(current);
+            }
+            let recorded = session
+                .frames
+                .get(current)
+                .and_then(|frame| frame.freq)
+                .map_or_else(String::new, |freq| {
+                    utils::midi_note_number_to_str(freq_to_midi_note(&freq).round() as f64)
+                });
+            ui.label(format!("記録時のピッチ: {recorded}"))
+                .on_hover_text("記録時に検出されたピッチ");
+            osc_changed = ui
+                .checkbox(&mut self.replay_sends_osc, "OSC")
+                .on_hover_text("再生中の解析結果を OSC で送信する")
+                .changed();
+            close = ui.button("閉じる").clicked();
+        });
+        if close {
+            self.replay = None;
+        } else if osc_changed {
+            let replay = self.replay.take().unwrap();
+            let current = replay.current();
+            let session = replay.session.clone();
+            drop(replay);
+            self.open_replay(session, current);
+        }
+    }
+
+    /// Shows the profile selector and the buttons to manage profiles. Returns whether the
+    /// analyzer options have been changed.
+    fn profile_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let settings = &mut self.settings;
+        ui.horizontal(|ui| {
+            ui.label("プロファイル");
+            let active = settings.active_profile.clone();
+            let mut selected_text = active.clone().unwrap_or_else(|| "-".to_owned());
+            if settings.is_profile_modified() {
+                selected_text.push('*');
+            }
+            egui::ComboBox::from_id_salt("profile")
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    for profile in settings.all_profiles() {
+                        let selected = active.as_deref() == Some(profile.name.as_str());
+                        if ui.selectable_label(selected, &profile.name).clicked() {
+                            changed |= settings.apply_profile(&profile.name);
+                            self.profile_name.clone_from(&profile.name);
+                        }
+                    }
+                });
+            if ui.button("読込").clicked() {
+                let file = rfd::FileDialog::new()
+                    .add_filter("JSON", &["json"])
+                    .pick_file();
+                if let Some(path) = file {
+                    self.profile_message = match Profile::import(&path) {
+                        Ok(profile) => {
+                            let name = profile.name.clone();
+                            if settings.add_profile(profile) {
+                                changed |= settings.apply_profile(&name);
+                                None
+                            } else {
+                                Some(format!("{name} は組み込みのプロファイルです"))
+                            }
+                        }
+                        Err(e) => Some(format!("読み込みに失敗しました: {e}")),
+                    };
+                }
+            }
+            if ui.button("書出").clicked() {
+                let name = active.unwrap_or_else(|| "profile".to_owned());
+                let file = rfd::FileDialog::new()
+                    .add_filter("JSON", &["json"])
+                    .set_file_name(format!("{name}.json"))
+                    .save_file();
+                if let Some(path) = file {
+                    let profile = settings.current_profile(&name);
+                    self.profile_message = profile
+                        .export(&path)
+                        .err()
+                        .map(|e| format!("書き出しに失敗しました: {e}"));
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            let name_edit = egui::TextEdit::singleline(&mut self.profile_name)
+                .hint_text("プロファイル名")
+                .desired_width(160.0);
+            ui.add(name_edit);
+            let name = self.profile_name.trim().to_owned();
+            let is_builtin = Settings::is_builtin_profile(&name);
+            let can_save = !name.is_empty() && !is_builtin;
+            if ui
+                .add_enabled(can_save, egui::Button::new("保存"))
+                .clicked()
+            {
+                settings.add_profile(settings.current_profile(&name));
+                settings.active_profile = Some(name.clone());
+            }
+            let can_delete = settings.profiles.iter().any(|p| p.name == name);
+            if ui
+                .add_enabled(can_delete, egui::Button::new("削除"))
+                .clicked()
+            {
+                settings.delete_profile(&name);
+            }
+        });
+        if let Some(message) = &self.profile_message {
+            ui.colored_label(egui::Color32::LIGHT_RED, message);
+        }
+        changed
+    }
+
+    fn save_settings(&mut self) {
+        if self.saves_settings && self.settings != self.saved_settings {
+            // Settings are a convenience; failing to write them must not stop the analyzer.
+            self.settings_save_message = self
+                .settings
+                .save()
+                .err()
+                .map(|e| format!("設定を保存できません: {e}"));
+            self.saved_settings = self.settings.clone();
+        }
+        self.saved_at = Instant::now();
     }
 }
 
@@ -68,15 +895,34 @@ impl eframe::App for App {
             .show(ctx, |ui| {
                 ui.add_space(10.0);
                 ui.horizontal(|ui| {
-                    ui.add_enabled_ui(!self.is_running(), |ui| {
-                        let mut selected_text = self.device_list.device().name.clone();
-                        selected_text.elipsis(27);
-                        let combobox = egui::ComboBox::from_label("").selected_text(selected_text);
-                        combobox.show_ui(ui, |ui| {
-                            for (i, device) in self.device_list.devices.iter().enumerate() {
-                                ui.selectable_value(&mut self.device_list.index, i, &device.name);
-                            }
-                        });
+                    let mut selected_text = self
+                        .device_list
+                        .device()
+                        .map_or_else(|| "デバイスなし".to_owned(), device_label);
+                    selected_text.elipsis(27);
+                    let combobox = egui::ComboBox::from_label("").selected_text(selected_text);
+                    let mut device_changed = false;
+                    combobox.show_ui(ui, |ui| {
+                        for (i, device) in self.device_list.devices.iter().enumerate() {
+                            let text = device_label(device);
+                            let resp = ui.selectable_value(&mut self.device_list.index, i, text);
+                            device_changed |= resp.changed();
+                        }
+                    });
+                    if device_changed {
+                        // Choosing a device by hand stops following the system default
+                        self.settings.follow_default_device = false;
+                        self.change_device();
+                    }
+                    if ui
+                        .small_button("🔄")
+                        .on_hover_text("デバイス一覧を更新する")
+                        .clicked()
+                    {
+                        self.refresh_devices();
+                    }
+                    let can_start = !self.is_running() && self.device_list.device().is_some();
+                    ui.add_enabled_ui(can_start, |ui| {
                         if ui.button("Start").clicked() {
                             self.start();
                         }
@@ -89,39 +935,673 @@ impl eframe::App for App {
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                         let icon = egui::Image::new(egui::include_image!("icons/keep.svg"))
                             .fit_to_exact_size(egui::vec2(ICON_SIZE, ICON_SIZE));
-                        ui.toggle_value(&mut self.force_show_graph, icon)
+                        ui.toggle_value(&mut self.settings.ui.force_show_graph, icon)
                             .on_hover_text("常にグラフを表示する");
                     });
                 });
+                if let Some(error) = self
+                    .analyzer
+                    .as_ref()
+                    .and_then(|a| a.results.device_error())
+                {
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        format!("入力デバイスを使用できません（再接続を待っています）: {error}"),
+                    );
+                }
+                if let Some(error) = self.osc_analyzer().and_then(|a| a.results.osc_error()) {
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        format!("OSC を送信できません: {error}"),
+                    );
+                }
+                let settings_messages = [&self.settings_load_message, &self.settings_save_message];
+                for message in settings_messages.into_iter().flatten() {
+                    ui.colored_label(egui::Color32::LIGHT_RED, message);
+                }
+                self.replay_ui(ui);
+                egui::CollapsingHeader::new("設定").show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        let follow = &mut self.settings.follow_default_device;
+                        if ui.checkbox(follow, "既定のデバイスに追従").changed() && *follow
+                        {
+                            self.refresh_devices();
+                        }
+                        let channels = self.device_list.device().map_or(1, |d| d.channels);
+                        let channel_mode = &mut self.settings.analyzer.channel_mode;
+                        if channel_ui(ui, channel_mode, channels) {
+                            self.apply_options();
+                        }
+                    });
+                    let mut changed = self.profile_ui(ui);
+                    changed |= options_ui(ui, &mut self.settings.analyzer);
+                    changed |= osc_ui(ui, &mut self.settings.osc, &mut self.mapping_message);
+                    changed |= self.avatar_ui(ui);
+                    changed |= self.overlay_ui(ui);
+                    self.osc_recording_ui(ui);
+                    self.compare_ui(ui);
+                    self.melody_ui(ui);
+                    self.pitch_recorder_ui(ui);
+                    self.session_ui(ui);
+                    if changed {
+                        self.apply_options();
+                    }
+                    let ui_settings = &mut self.settings.ui;
+                    spectrogram_ui(ui, &mut ui_settings.spectrogram);
+                    vowel_chart_ui(
+                        ui,
+                        &mut ui_settings.show_vowel_chart,
+                        &mut ui_settings.vowel_reference,
+                    );
+                });
             });
+        if let Some(replay) = &self.replay {
+            replay.sync_options();
+        }
         let is_focused = ctx.input(|i| i.focused);
-        if (self.force_show_graph || is_focused) && self.is_running() {
-            let analyzer = self.analyzer.as_ref().unwrap();
-            update_bottom(analyzer, ctx);
-            update_main(analyzer, &mut self.analyzer_options.gain, ctx);
+        // A replay is shown in place of the live input
+        let shown = self
+            .replay
+            .as_ref()
+            .map(|r| &r.analyzer)
+            .or(self.analyzer.as_ref());
+        if let (true, Some(analyzer)) = (self.settings.ui.force_show_graph || is_focused, shown) {
+            let ui_settings = &mut self.settings.ui;
+            let layout = &mut ui_settings.layout;
+            let comparison = self.comparison.as_ref();
+            update_bottom(analyzer, comparison, layout, ctx);
+            if ui_settings.show_vowel_chart {
+                let reference = ui_settings.vowel_reference;
+                update_vowel_chart(analyzer, comparison, reference, layout, ctx);
+            }
+            if ui_settings.spectrogram.visible {
+                let options = &ui_settings.spectrogram;
+                let seconds = self.settings.analyzer.spectrogram_seconds;
+                let view = &mut self.spectrogram;
+                update_spectrogram(analyzer, view, options, seconds, layout, ctx);
+            }
+            update_main(
+                analyzer,
+                comparison,
+                &mut self.settings.analyzer,
+                layout,
+                ctx,
+            );
             ctx.request_repaint();
         } else {
             egui::CentralPanel::default().show(ctx, |ui| {
-                if self.is_running() {
+                if shown.is_some() {
                     ui.heading("実行中...");
                     ui.label("リソースの節約のためにグラフを非表示にしています。");
                 }
             });
         }
+        self.follow_avatar();
+        if self.devices_polled_at.elapsed() > DEVICE_POLL_INTERVAL {
+            self.refresh_devices();
+        }
+        ctx.request_repaint_after(DEVICE_POLL_INTERVAL);
+        if self.saved_at.elapsed() > SAVE_INTERVAL {
+            self.save_settings();
+        }
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.save_settings();
+    }
+}
+
+fn options_ui(ui: &mut egui::Ui, options: &mut AnalyzerOptions) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("検出");
+        egui::ComboBox::from_id_salt("pitch_algorithm")
+            .selected_text(pitch_algorithm_to_str(options.pitch_algorithm))
+            .show_ui(ui, |ui| {
+                for algorithm in [
+                    PitchAlgorithm::McLeod,
+                    PitchAlgorithm::Yin,
+                    PitchAlgorithm::Autocorrelation,
+                ] {
+                    let text = pitch_algorithm_to_str(algorithm);
+                    let resp = ui.selectable_value(&mut options.pitch_algorithm, algorithm, text);
+                    changed |= resp.changed();
+                }
+            });
+        ui.label("FFT");
+        egui::ComboBox::from_id_salt("fft_size")
+            .selected_text(options.fft_size.to_string())
+            .show_ui(ui, |ui| {
+                for size in FFT_SIZES {
+                    let text = size.to_string();
+                    changed |= ui
+                        .selectable_value(&mut options.fft_size, size, text)
+                        .changed();
+                }
+            });
+        ui.label("LPC");
+        let lpc_order = egui::DragValue::new(&mut options.lpc_order).range(LPC_ORDERS);
+        changed |= ui.add(lpc_order).changed();
+    });
+    ui.horizontal(|ui| {
+        changed |= ui.checkbox(&mut options.gate_enabled, "ゲート").changed();
+        let gate_db = egui::DragValue::new(&mut options.gate_db)
+            .range(-90.0..=0.0)
+            .suffix("dB");
+        changed |= ui.add_enabled(options.gate_enabled, gate_db).changed();
+    });
+    ui.horizontal(|ui| {
+        changed |= pitch_range_ui(ui, &mut options.pitch_range);
+        changed |= ui
+            .checkbox(&mut options.send_pitch_range, "OSC")
+            .on_hover_text("音域を FT_Min / FT_Max として送信する")
+            .changed();
+    });
+    ui.horizontal(|ui| {
+        ui.label("倍音");
+        let harmonics = egui::DragValue::new(&mut options.harmonics).range(1..=MAX_HARMONICS);
+        changed |= ui.add(harmonics).changed();
+        egui::ComboBox::from_id_salt("harmonic_method")
+            .selected_text(harmonic_method_to_str(options.harmonic_method))
+            .show_ui(ui, |ui| {
+                for method in [HarmonicMethod::LocalPeak, HarmonicMethod::PeakPick] {
+                    let text = harmonic_method_to_str(method);
+                    let resp = ui.selectable_value(&mut options.harmonic_method, method, text);
+                    changed |= resp.changed();
+                }
+            });
+        egui::ComboBox::from_id_salt("gain_scale")
+            .selected_text(gain_scale_to_str(options.gain_scale))
+            .show_ui(ui, |ui| {
+                for scale in [GainScale::Db, GainScale::Linear] {
+                    let text = gain_scale_to_str(scale);
+                    changed |= ui
+                        .selectable_value(&mut options.gain_scale, scale, text)
+                        .changed();
+                }
+            });
+    });
+    ui.horizontal(|ui| {
+        ui.label("履歴");
+        let seconds = egui::DragValue::new(&mut options.spectrogram_seconds)
+            .range(1.0..=60.0)
+            .suffix("s");
+        changed |= ui.add(seconds).changed();
+    });
+    changed
+}
+
+/// Starts and stops recording a session. The path of the saved WAV file goes to `saved`.
+fn session_recorder_ui(
+    ui: &mut egui::Ui,
+    analyzer: &Analyzer,
+    saved: &mut Option<String>,
+    message: &mut Option<String>,
+) {
+    let mut recorders = analyzer.recorders.lock().unwrap();
+    if let Some(error) = recorders.session_error.take() {
+        *message = Some(format!("記録を中断しました: {error}"));
+    }
+    match &recorders.session {
+        None => {
+            if ui
+                .button("記録")
+                .on_hover_text("入力音声と解析結果をファイルに記録する")
+                .clicked()
+            {
+                let session = Settings::sessions_dir()
+                    .ok_or("設定フォルダが見つかりません".into())
+                    .and_then(|dir| SessionRecorder::new(&dir));
+                *saved = None;
+                *message = None;
+                match session {
+                    Ok(session) => recorders.session = Some(session),
+                    Err(e) => *message = Some(format!("記録を開始できません: {e}")),
+                }
+            }
+        }
+        Some(session) => {
+            let stop = ui.button("停止").clicked();
+            ui.label(format!("{} ({:.0}s)", session.id, session.seconds()));
+            if stop {
+                match recorders.session.take().unwrap().finish() {
+                    Ok(paths) => *saved = Some(paths.wav.display().to_string()),
+                    Err(e) => *message = Some(format!("保存に失敗しました: {e}")),
+                }
+            }
+        }
+    }
+}
+
+fn device_label(device: &Device) -> String {
+    if device.connected {
+        device.name.clone()
+    } else {
+        format!("{}（切断）", device.name)
+    }
+}
+
+fn channel_ui(ui: &mut egui::Ui, mode: &mut ChannelMode, channels: usize) -> bool {
+    let mut changed = false;
+    ui.label("チャンネル");
+    egui::ComboBox::from_id_salt("channel_mode")
+        .selected_text(channel_mode_to_str(*mode))
+        .show_ui(ui, |ui| {
+            let modes = [ChannelMode::Sum, ChannelMode::MaxEnergy]
+                .into_iter()
+                .chain((0..channels).map(ChannelMode::Channel));
+            for m in modes {
+                changed |= ui
+                    .selectable_value(mode, m, channel_mode_to_str(m))
+                    .changed();
+            }
+        });
+    changed
+}
+
+fn compare_source_to_str(source: &CompareSource, devices: &DeviceList) -> String {
+    match source {
+        CompareSource::Device { id, .. } => {
+            let device = devices.devices.iter().find(|d| d.id == *id);
+            let mut name = device.map_or_else(|| "不明なデバイス".to_owned(), device_label);
+            name.elipsis(27);
+            name
+        }
+        CompareSource::File(path) => path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+    }
+}
+
+fn osc_ui(
+    ui: &mut egui::Ui,
+    options: &mut OscOptions,
+    mapping_message: &mut Option<String>,
+) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("送信先");
+        changed = address_ui(ui, "osc_target", &mut options.target);
+    });
+    changed |= mapping_ui(ui, &mut options.mapping, mapping_message);
+    changed |= smoothing_ui(ui, &mut options.smoothing);
+    changed |= chatbox_ui(ui, &mut options.chatbox);
+    ui.horizontal(|ui| {
+        let mut limited = options.max_rate.is_some();
+        changed |= ui.checkbox(&mut limited, "送信レート上限").changed();
+        let mut rate = options.max_rate.unwrap_or(DEFAULT_MAX_RATE);
+        let drag = egui::DragValue::new(&mut rate)
+            .range(MAX_RATES)
+            .suffix("Hz");
+        changed |= ui.add_enabled(limited, drag).changed();
+        options.max_rate = limited.then_some(rate);
+    });
+    ui.horizontal(|ui| {
+        changed |= ui
+            .checkbox(&mut options.changes_only, "変化時のみ")
+            .on_hover_text(
+                "閾値を超えて変化したパラメーターだけを送信する（1 秒ごとにすべて送信する）",
+            )
+            .changed();
+        let thresholds = &mut options.thresholds;
+        ui.add_enabled_ui(options.changes_only, |ui| {
+            for (label, threshold) in [
+                ("ピッチ", &mut thresholds.pitch),
+                ("倍音", &mut thresholds.gains),
+                ("フォルマント", &mut thresholds.formants),
+            ] {
+                ui.label(label);
+                let drag = egui::DragValue::new(threshold)
+                    .range(0.0..=0.1)
+                    .speed(0.0005)
+                    .max_decimals(4);
+                changed |= ui.add(drag).changed();
+            }
+        });
+    });
+    ui.horizontal(|ui| {
+        changed |= ui
+            .checkbox(&mut options.bundle, "バンドル")
+            .on_hover_text("1 フレームのメッセージをまとめて送信する")
+            .changed();
+        ui.label("最大");
+        let payload = egui::DragValue::new(&mut options.max_payload)
+            .range(MAX_PAYLOADS)
+            .suffix(" bytes");
+        changed |= ui
+            .add_enabled(options.bundle, payload)
+            .on_hover_text("これを超えるバンドルは分割して送信する")
+            .changed();
+    });
+    changed
+}
+
+/// Output rules are edited as JSON outside the app, so only importing and exporting them is
+/// offered here.
+fn mapping_ui(ui: &mut egui::Ui, mapping: &mut Mapping, message: &mut Option<String>) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("マッピング");
+        let is_default = *mapping == Mapping::default();
+        let label = if is_default {
+            "既定".to_owned()
+        } else {
+            format!("{} 件のルール", mapping.rules.len())
+        };
+        let addresses: Vec<&str> = mapping.rules.iter().map(|r| r.address.as_str()).collect();
+        ui.label(label).on_hover_text(addresses.join("\n"));
+        if ui.button("読込").clicked() {
+            let file = rfd::FileDialog::new()
+                .add_filter("JSON", &["json"])
+                .pick_file();
+            if let Some(path) = file {
+                *message = match Mapping::import(&path) {
+                    Ok(imported) => {
+                        *mapping = imported;
+                        changed = true;
+                        None
+                    }
+                    Err(e) => Some(format!("読み込みに失敗しました: {e}")),
+                };
+            }
+        }
+        if ui.button("書出").clicked() {
+            let file = rfd::FileDialog::new()
+                .add_filter("JSON", &["json"])
+                .set_file_name("mapping.json")
+                .save_file();
+            if let Some(path) = file {
+                *message = mapping
+                    .export(&path)
+                    .err()
+                    .map(|e| format!("書き出しに失敗しました: {e}"));
+            }
+        }
+        if ui
+            .add_enabled(!is_default, egui::Button::new("既定に戻す"))
+            .clicked()
+        {
+            *mapping = Mapping::default();
+            *message = None;
+            changed = true;
+        }
+    });
+    if let Some(message) = message {
+        ui.colored_label(egui::Color32::LIGHT_RED, message.as_str());
+    }
+    changed
+}
+
+fn chatbox_ui(ui: &mut egui::Ui, options: &mut ChatboxOptions) -> bool {
+    let mut changed = false;
+    let error = Template::parse(&options.template).err();
+    ui.horizontal(|ui| {
+        changed |= ui
+            .checkbox(&mut options.enabled, "チャットボックス")
+            .on_hover_text("ピッチなどをチャットボックスに表示する")
+            .changed();
+        let template = egui::TextEdit::singleline(&mut options.template)
+            .desired_width(260.0)
+            .text_color_opt(error.is_some().then_some(egui::Color32::LIGHT_RED));
+        changed |= ui
+            .add(template)
+            .on_hover_text(
+                "{note} 音名 / {cents} 音名からのずれ (cent) / {freq} ピッチ (Hz) / \
+                 {f1} から {f4} フォルマント (Hz) / {vowel} 母音 / {volume} 音量 (dBFS)",
+            )
+            .changed();
+        if ui.small_button("↺").on_hover_text("既定に戻す").clicked() {
+            options.template = DEFAULT_TEMPLATE.to_owned();
+            changed = true;
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("間隔");
+        let interval = egui::DragValue::new(&mut options.interval)
+            .range(INTERVALS)
+            .speed(0.1)
+            .suffix("s");
+        changed |= ui.add(interval).changed();
+        ui.label("母音");
+        egui::ComboBox::from_id_salt("chatbox_vowels")
+            .selected_text(options.vowels.name())
+            .show_ui(ui, |ui| {
+                for set in VowelSet::ALL {
+                    changed |= ui
+                        .selectable_value(&mut options.vowels, set, set.name())
+                        .changed();
+                }
+            });
+    });
+    if let Some(error) = error {
+        ui.colored_label(egui::Color32::LIGHT_RED, error);
+    }
+    changed
+}
+
+fn smoothing_ui(ui: &mut egui::Ui, options: &mut SmoothingOptions) -> bool {
+    let mut changed = false;
+    for (label, smoothing) in [
+        ("ピッチ", &mut options.pitch),
+        ("倍音", &mut options.gains),
+        ("フォルマント", &mut options.formants),
+    ] {
+        ui.horizontal(|ui| {
+            ui.label(format!("平滑化 ({label})"));
+            egui::ComboBox::from_id_salt(("smoothing", label))
+                .selected_text(smoothing_to_str(smoothing))
+                .show_ui(ui, |ui| {
+                    for kind in Smoothing::KINDS {
+                        let selected = smoothing.is_same_kind(&kind);
+                        if ui
+                            .selectable_label(selected, smoothing_to_str(&kind))
+                            .clicked()
+                            && !selected
+                        {
+                            *smoothing = kind;
+                            changed = true;
+                        }
+                    }
+                });
+            let mut add = |value: egui::DragValue| changed |= ui.add(value).changed();
+            match smoothing {
+                Smoothing::None => {}
+                Smoothing::Ema { time_constant } => {
+                    add(egui::DragValue::new(time_constant)
+                        .range(0.005..=2.0)
+                        .speed(0.005)
+                        .suffix("s"));
+                }
+                Smoothing::OneEuro { min_cutoff, beta } => {
+                    add(egui::DragValue::new(min_cutoff)
+                        .range(0.01..=20.0)
+                        .speed(0.05)
+                        .prefix("fc ")
+                        .suffix("Hz"));
+                    add(egui::DragValue::new(beta)
+                        .range(0.0..=100.0)
+                        .speed(0.1)
+                        .prefix("β "));
+                }
+                Smoothing::Median { window } => {
+                    add(egui::DragValue::new(window)
+                        .range(1..=31)
+                        .suffix(" フレーム"));
+                }
+                Smoothing::SlewRate { max_rate } => {
+                    add(egui::DragValue::new(max_rate)
+                        .range(0.01..=50.0)
+                        .speed(0.05)
+                        .suffix("/s"));
+                }
+            }
+        });
+    }
+    changed
+}
+
+fn address_ui(ui: &mut egui::Ui, id_salt: &str, address: &mut SocketAddrV4) -> bool {
+    // Keep the text being edited until it is a valid address
+    let id = ui.id().with(id_salt);
+    let mut text = ui.data_mut(|d| {
+        d.get_temp_mut_or_insert_with(id, || address.to_string())
+            .clone()
+    });
+    // Port 0 parses but cannot be sent to
+    let parse = |text: &str| {
+        text.parse::<SocketAddrV4>()
+            .ok()
+            .filter(|address| address.port() != 0)
+    };
+    let valid = parse(&text).is_some();
+    let text_edit = egui::TextEdit::singleline(&mut text)
+        .desired_width(140.0)
+        .text_color_opt((!valid).then_some(egui::Color32::LIGHT_RED));
+    let mut changed = false;
+    if ui.add(text_edit).changed() {
+        if let Some(parsed) = parse(&text) {
+            *address = parsed;
+            changed = true;
+        }
+        ui.data_mut(|d| d.insert_temp(id, text));
+    }
+    changed
+}
+
+/// Starts and stops recording the OSC packets the analyzer sends.
+fn osc_recorder_ui(ui: &mut egui::Ui, analyzer: &Analyzer, message: &mut Option<String>) {
+    let mut recorders = analyzer.recorders.lock().unwrap();
+    let Some(recorder) = &recorders.osc else {
+        // Do not block the analyzer while the dialog is open
+        drop(recorders);
+        let record = ui
+            .button("記録...")
+            .on_hover_text("送信した OSC をファイルに記録する");
+        if !record.clicked() {
+            return;
+        }
+        let file = rfd::FileDialog::new()
+            .add_filter("OSC", &["jsonl"])
+            .set_file_name("osc.jsonl")
+            .save_file();
+        if let Some(path) = file {
+            *message = None;
+            match OscRecorder::create(&path) {
+                Ok(recorder) => analyzer.recorders.lock().unwrap().osc = Some(recorder),
+                Err(e) => *message = Some(format!("記録を開始できません: {e}")),
+            }
+        }
+        return;
+    };
+    let stop = ui.button("停止").clicked();
+    ui.label(format!(
+        "{} パケット ({:.0}s)",
+        recorder.packets,
+        recorder.seconds()
+    ));
+    if let Some(error) = &recorder.error {
+        *message = Some(format!("記録に失敗しました: {error}"));
     }
+    if stop {
+        if let Err(e) = recorders.osc.take().unwrap().finish() {
+            *message = Some(format!("記録に失敗しました: {e}"));
+        }
+    }
+}
+
+fn spectrogram_ui(ui: &mut egui::Ui, view: &mut SpectrogramOptions) {
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut view.visible, "スペクトログラム");
+        egui::ComboBox::from_id_salt("colormap")
+            .selected_text(view.colormap.name())
+            .show_ui(ui, |ui| {
+                for colormap in Colormap::ALL {
+                    ui.selectable_value(&mut view.colormap, colormap, colormap.name());
+                }
+            });
+        egui::ComboBox::from_id_salt("freq_scale")
+            .selected_text(view.scale.name())
+            .show_ui(ui, |ui| {
+                for scale in FreqScale::ALL {
+                    ui.selectable_value(&mut view.scale, scale, scale.name());
+                }
+            });
+    });
+}
+
+fn vowel_chart_ui(ui: &mut egui::Ui, visible: &mut bool, reference: &mut Option<VowelSet>) {
+    ui.horizontal(|ui| {
+        ui.checkbox(visible, "母音チャート");
+        let selected_text = reference.map_or("参照なし", |set| set.name());
+        egui::ComboBox::from_id_salt("vowel_reference")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(reference, None, "参照なし");
+                for set in VowelSet::ALL {
+                    ui.selectable_value(reference, Some(set), set.name());
+                }
+            });
+    });
 }
 
-fn update_main(analyzer: &Analyzer, gain: &mut f32, ctx: &egui::Context) {
+fn pitch_range_ui(ui: &mut egui::Ui, range: &mut PitchRange) -> bool {
+    let mut changed = false;
+    ui.label("音域");
+    egui::ComboBox::from_id_salt("pitch_range")
+        .selected_text(pitch_range_to_str(range))
+        .show_ui(ui, |ui| {
+            for preset in PitchRange::PRESETS {
+                let text = pitch_range_to_str(&preset);
+                changed |= ui.selectable_value(range, preset, text).changed();
+            }
+        });
+    let (low, high) = (range.low, range.high);
+    let note_formatter = |n: f64, _| utils::midi_note_number_to_str(n);
+    let low_value = egui::DragValue::new(&mut range.low)
+        .range(0..=high.saturating_sub(1))
+        .custom_formatter(note_formatter);
+    changed |= ui.add(low_value).changed();
+    let high_value = egui::DragValue::new(&mut range.high)
+        .range(low.saturating_add(1)..=127)
+        .custom_formatter(note_formatter);
+    changed |= ui.add(high_value).changed();
+    changed
+}
+
+fn update_main(
+    analyzer: &Analyzer,
+    comparison: Option<&Analyzer>,
+    options: &mut AnalyzerOptions,
+    layout: &mut Layout,
+    ctx: &egui::Context,
+) {
+    let to_points = |freq_history: Vec<f32>| -> PlotPoints {
+        freq_history
+            .into_iter()
+            .enumerate()
+            .map(|(i, midinote)| [i as f64, midinote as f64])
+            .collect()
+    };
     let freq_history = analyzer.results.freq_history_in_midi_note();
     let history_len = freq_history.len() as f64;
-    let pitch_points: PlotPoints = freq_history
-        .into_iter()
-        .enumerate()
-        .map(|(i, midinote)| [i as f64, midinote as f64])
-        .collect();
-    let pitch = Line::new("pitch", pitch_points)
+    let pitch = Line::new("pitch", to_points(freq_history))
         .color(egui::Color32::YELLOW)
         .width(3.0);
+    let comparison_pitch = comparison.map(|comparison| {
+        let freq_history = comparison.results.freq_history_in_midi_note();
+        Line::new("comparison", to_points(freq_history))
+            .color(COMPARISON_COLOR)
+            .width(2.0)
+    });
+    let difference = comparison.map(|c| Difference::new(&analyzer.results, &c.results));
+    let (piano_roll, score) = match &analyzer.recorders.lock().unwrap().practice {
+        Some(practice) => {
+            let frame_period = analyzer.results.frame_period() as f64;
+            let piano_roll = piano_roll(practice, history_len, frame_period);
+            (piano_roll, Some(score_to_str(practice)))
+        }
+        None => (vec![], None),
+    };
 
     let spectrum = analyzer.results.spectrum_in_midi_note();
     let spec_points: PlotPoints = spectrum
@@ -141,26 +1621,51 @@ fn update_main(analyzer: &Analyzer, gain: &mut f32, ctx: &egui::Context) {
 
     egui::CentralPanel::default().show(ctx, |ui| {
         ui.style_mut().spacing.slider_width = 280.0;
-        let slider = egui::Slider::new(gain, -24.0..=24.0).suffix("dB");
+        let slider = egui::Slider::new(&mut options.gain, -24.0..=24.0).suffix("dB");
         let resp = ui.add(slider);
         if resp.changed() {
-            analyzer.options.write().unwrap().gain = *gain;
+            analyzer.options.write().unwrap().gain = options.gain;
         }
         ui.add(progress_bar);
+        if let Some(difference) = difference {
+            ui.colored_label(COMPARISON_COLOR, difference_to_str(&difference));
+        }
+        if let Some(score) = score {
+            ui.label(score);
+        }
         ui.add_space(10.0);
-        Plot::new("plot")
+        let range = options.pitch_range;
+        let (low, high) = (range.low as f64, range.high as f64);
+        let plot_id = format!("pitch_{}_{}", range.low, range.high);
+        let plot = Plot::new(("plot", range))
             .show_x(false)
-            .y_axis_formatter(|g, _r| midi_note_number_to_str(g.value))
+            .y_axis_formatter(|g, _r| utils::midi_note_number_to_str(g.value))
             .show_axes([false, true])
             .default_x_bounds(0.0, history_len)
+            .default_y_bounds(low - 1.0, high + 1.0)
             .show(ui, |plot_ui| {
+                layout.restore_plot(&plot_id, plot_ui);
+                piano_roll
+                    .into_iter()
+                    .for_each(|note| plot_ui.polygon(note));
                 plot_ui.line(spec);
+                plot_ui.hline(HLine::new("range", low).color(egui::Color32::DARK_GRAY));
+                plot_ui.hline(HLine::new("range", high).color(egui::Color32::DARK_GRAY));
+                if let Some(line) = comparison_pitch {
+                    plot_ui.line(line);
+                }
                 plot_ui.line(pitch);
             });
+        layout.remember_plot(&plot_id, &plot);
     });
 }
 
-fn update_bottom(analyzer: &Analyzer, ctx: &egui::Context) {
+fn update_bottom(
+    analyzer: &Analyzer,
+    comparison: Option<&Analyzer>,
+    layout: &mut Layout,
+    ctx: &egui::Context,
+) {
     let gains = analyzer.results.gains();
     let gains_bars: Vec<Bar> = gains
         .into_iter()
@@ -169,16 +1674,19 @@ fn update_bottom(analyzer: &Analyzer, ctx: &egui::Context) {
         .collect();
     let gains_bars = BarChart::new("gains", gains_bars);
 
-    egui::TopBottomPanel::bottom("bottom")
-        .default_height(80.0)
+    let panel = egui::TopBottomPanel::bottom("bottom")
+        .default_height(layout.panel_height("bottom", 80.0))
         .resizable(true)
         .show(ctx, |ui| {
-            Plot::new("gains")
+            let plot = Plot::new("gains")
                 .default_y_bounds(0.0, 1.0)
                 .show(ui, |plot_ui| {
+                    layout.restore_plot("gains", plot_ui);
                     plot_ui.bar_chart(gains_bars);
                 });
+            layout.remember_plot("gains", &plot);
         });
+    layout.set_panel_height("bottom", panel.response.rect.height());
 
     let (min, max) = (-80.0, 60.0);
     let formant_spec = analyzer.results.formant_spec();
@@ -196,6 +1704,7 @@ fn update_bottom(analyzer: &Analyzer, ctx: &egui::Context) {
     let spec = Line::new("pitch", spec_points).color(egui::Color32::CYAN);
 
     let peaks = analyzer.results.formant_peak();
+    let comparison_peaks = comparison.map_or(vec![], |c| c.results.formant_peak());
     let colors = [
         egui::Color32::RED,
         egui::Color32::GREEN,
@@ -203,11 +1712,12 @@ fn update_bottom(analyzer: &Analyzer, ctx: &egui::Context) {
         egui::Color32::MAGENTA,
     ];
 
-    egui::TopBottomPanel::bottom("formant")
-        .default_height(100.0)
+    let panel = egui::TopBottomPanel::bottom("formant")
+        .default_height(layout.panel_height("formant", 100.0))
         .resizable(true)
         .show(ctx, |ui| {
-            Plot::new("formant").show(ui, |plot_ui| {
+            let plot = Plot::new("formant").show(ui, |plot_ui| {
+                layout.restore_plot("formant", plot_ui);
                 plot_ui.line(formantspec_line);
                 plot_ui.line(spec);
                 peaks.iter().take(4).zip(colors).for_each(|(&f, c)| {
@@ -215,16 +1725,234 @@ fn update_bottom(analyzer: &Analyzer, ctx: &egui::Context) {
                     let line = Line::new("peak", points).color(c);
                     plot_ui.line(line);
                 });
+                comparison_peaks
+                    .iter()
+                    .take(4)
+                    .zip(colors)
+                    .for_each(|(&f, c)| {
+                        let points: PlotPoints = vec![[f, min], [f, max]].into();
+                        let line = Line::new("comparison", points)
+                            .color(c)
+                            .style(LineStyle::dashed_loose());
+                        plot_ui.line(line);
+                    });
             });
+            layout.remember_plot("formant", &plot);
         });
+    layout.set_panel_height("formant", panel.response.rect.height());
 }
 
-fn midi_note_number_to_str(n: f64) -> String {
-    if !(0.0..=150.0).contains(&n) {
-        return "".into();
+/// Draws the notes of the melody around the current time, at the right end of the pitch
+/// history, coloring the notes already sung by how much of them was in tune.
+fn piano_roll(practice: &Practice, history_len: f64, frame_period: f64) -> Vec<Polygon<'static>> {
+    let elapsed = practice.elapsed();
+    let to_x = |seconds: f64| history_len - 1.0 - (elapsed - seconds) / frame_period;
+    let target = practice.target();
+    practice
+        .melody
+        .notes
+        .iter()
+        .zip(&practice.scores)
+        .filter(|(note, _)| to_x(note.end) > 0.0 && to_x(note.start) < history_len * 2.0)
+        .map(|(note, score)| {
+            let (x0, x1) = (to_x(note.start), to_x(note.end));
+            let (y0, y1) = (note.key as f64 - 0.5, note.key as f64 + 0.5);
+            let points = vec![[x0, y0], [x1, y0], [x1, y1], [x0, y1]];
+            let fill = match score.in_tune_ratio() {
+                Some(ratio) => {
+                    let red = (255.0 * (1.0 - ratio)) as u8;
+                    let green = (255.0 * ratio) as u8;
+                    egui::Color32::from_rgb(red, green, 0).gamma_multiply(0.4)
+                }
+                None => egui::Color32::from_gray(80),
+            };
+            let stroke_color = if target == Some(note) {
+                egui::Color32::WHITE
+            } else {
+                egui::Color32::from_gray(120)
+            };
+            Polygon::new("melody", points)
+                .fill_color(fill)
+                .stroke((1.0, stroke_color))
+        })
+        .collect()
+}
+
+fn score_to_str(practice: &Practice) -> String {
+    let total = practice.total();
+    let in_tune = total
+        .in_tune_ratio()
+        .map_or("-".to_owned(), |r| format!("{:.0}%", r * 100.0));
+    let error = total
+        .mean_cents_error()
+        .map_or("-".to_owned(), |e| format!("{e:.0} cent"));
+    let state = if practice.is_finished() {
+        "（終了）"
+    } else {
+        ""
+    };
+    format!("音程が合っていた時間 : {in_tune}  平均誤差 : {error}{state}")
+}
+
+fn difference_to_str(difference: &Difference) -> String {
+    let mut text = match difference.cents.zip(difference.hz) {
+        Some((cents, hz)) => format!("比較との差 : {cents:+.0} cent ({hz:+.1} Hz)"),
+        None => "比較との差 : -".to_owned(),
+    };
+    for (name, diff) in [("F1", difference.f1), ("F2", difference.f2)] {
+        if let Some(diff) = diff {
+            text.push_str(&format!("  {name} {diff:+.0} Hz"));
+        }
     }
-    let notes = [
-        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
-    ];
-    format!("{}{}", notes[n as usize % 12], n as isize / 12 - 1)
+    text
+}
+
+fn channel_mode_to_str(mode: ChannelMode) -> String {
+    match mode {
+        ChannelMode::Sum => "ミックス".to_owned(),
+        ChannelMode::MaxEnergy => "最大音量".to_owned(),
+        ChannelMode::Channel(i) => format!("ch {}", i + 1),
+    }
+}
+
+fn smoothing_to_str(smoothing: &Smoothing) -> &'static str {
+    match smoothing {
+        Smoothing::None => "なし",
+        Smoothing::Ema { .. } => "EMA",
+        Smoothing::OneEuro { .. } => "One Euro",
+        Smoothing::Median { .. } => "メディアン",
+        Smoothing::SlewRate { .. } => "変化率制限",
+    }
+}
+
+fn pitch_algorithm_to_str(algorithm: PitchAlgorithm) -> &'static str {
+    match algorithm {
+        PitchAlgorithm::McLeod => "McLeod",
+        PitchAlgorithm::Yin => "YIN",
+        PitchAlgorithm::Autocorrelation => "自己相関",
+    }
+}
+
+fn harmonic_method_to_str(method: HarmonicMethod) -> &'static str {
+    match method {
+        HarmonicMethod::LocalPeak => "近傍",
+        HarmonicMethod::PeakPick => "ピーク",
+    }
+}
+
+fn gain_scale_to_str(scale: GainScale) -> &'static str {
+    match scale {
+        GainScale::Db => "dB",
+        GainScale::Linear => "リニア",
+    }
+}
+
+fn pitch_range_to_str(range: &PitchRange) -> String {
+    let low = utils::midi_note_number_to_str(range.low as f64);
+    let high = utils::midi_note_number_to_str(range.high as f64);
+    format!("{low} - {high}")
+}
+
+fn update_spectrogram(
+    analyzer: &Analyzer,
+    view: &mut SpectrogramView,
+    options: &SpectrogramOptions,
+    seconds: f32,
+    layout: &mut Layout,
+    ctx: &egui::Context,
+) {
+    let history = analyzer.results.spectrum_history();
+    let duration = history.frames.len() as f32 * history.frame_period;
+    let texture = view.update_texture(ctx, &history, options);
+    let center = PlotPoint::new(-duration as f64 / 2.0, 0.5);
+    let image = PlotImage::new("spectrogram", texture.id(), center, [duration, 1.0]);
+    let scale = options.scale;
+
+    let panel = egui::TopBottomPanel::bottom("spectrogram")
+        .default_height(layout.panel_height("spectrogram", 120.0))
+        .resizable(true)
+        .show(ctx, |ui| {
+            Plot::new("spectrogram")
+                .default_y_bounds(0.0, 1.0)
+                .x_axis_formatter(|g, _r| format!("{:.0}s", g.value))
+                .y_axis_formatter(move |g, _r| format!("{:.0}", scale.freq_at(g.value as f32)))
+                .show(ui, |plot_ui| {
+                    plot_ui.set_plot_bounds_x(-seconds as f64..=0.0);
+                    plot_ui.image(image);
+                });
+        });
+    layout.set_panel_height("spectrogram", panel.response.rect.height());
+}
+
+/// Plots F2 against F1 with both axes reversed, so that the vowels sit where they do on the
+/// classic vowel chart (front vowels on the left, open vowels at the bottom).
+fn update_vowel_chart(
+    analyzer: &Analyzer,
+    comparison: Option<&Analyzer>,
+    reference: Option<VowelSet>,
+    layout: &mut Layout,
+    ctx: &egui::Context,
+) {
+    // The axes are reversed by negating both formants and their tick labels.
+    let to_point = |(f1, f2): (f64, f64)| [-f2, -f1];
+    let history = analyzer.results.formant_history();
+    let history_len = history.len();
+    let current = history.last().copied().flatten();
+    let comparison_current = comparison.and_then(|c| c.results.formant_history().pop().flatten());
+
+    let panel = egui::TopBottomPanel::bottom("vowel_chart")
+        .default_height(layout.panel_height("vowel_chart", 160.0))
+        .resizable(true)
+        .show(ctx, |ui| {
+            let plot = Plot::new("vowel_chart")
+                .default_x_bounds(-3200.0, -500.0)
+                .default_y_bounds(-1100.0, -200.0)
+                .x_axis_formatter(|g, _r| format!("{:.0}", -g.value))
+                .y_axis_formatter(|g, _r| format!("{:.0}", -g.value))
+                .x_axis_label("F2")
+                .y_axis_label("F1")
+                .show(ui, |plot_ui| {
+                    layout.restore_plot("vowel_chart", plot_ui);
+                    if let Some(set) = reference {
+                        let (f1_spread, f2_spread) = set.spread();
+                        for vowel in set.vowels() {
+                            let ellipse: PlotPoints = (0..48)
+                                .map(|i| {
+                                    let t = i as f64 / 48.0 * std::f64::consts::TAU;
+                                    let f1 = vowel.f1 + f1_spread * t.sin();
+                                    let f2 = vowel.f2 + f2_spread * t.cos();
+                                    to_point((f1, f2))
+                                })
+                                .collect();
+                            let color = egui::Color32::from_gray(120);
+                            plot_ui.polygon(Polygon::new("vowel", ellipse).stroke((1.0, color)));
+                            let center = to_point((vowel.f1, vowel.f2));
+                            let position = PlotPoint::new(center[0], center[1]);
+                            plot_ui.text(Text::new("vowel", position, vowel.symbol).color(color));
+                        }
+                    }
+                    history.windows(2).enumerate().for_each(|(i, pair)| {
+                        if let [Some(a), Some(b)] = pair {
+                            let alpha = (255 * (i + 1) / history_len) as u8;
+                            let color = egui::Color32::from_rgba_unmultiplied(255, 255, 0, alpha);
+                            let points: PlotPoints = vec![to_point(*a), to_point(*b)].into();
+                            plot_ui.line(Line::new("trail", points).color(color).width(2.0));
+                        }
+                    });
+                    if let Some(f1_f2) = comparison_current {
+                        let point = Points::new("comparison", vec![to_point(f1_f2)])
+                            .color(COMPARISON_COLOR)
+                            .radius(5.0);
+                        plot_ui.points(point);
+                    }
+                    if let Some(f1_f2) = current {
+                        let point = Points::new("current", vec![to_point(f1_f2)])
+                            .color(egui::Color32::YELLOW)
+                            .radius(5.0);
+                        plot_ui.points(point);
+                    }
+                });
+            layout.remember_plot("vowel_chart", &plot);
+        });
+    layout.set_panel_height("vowel_chart", panel.response.rect.height());
 }
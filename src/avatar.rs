@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use rosc::{decoder, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+
+use crate::mapping::Mapping;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// The port VRChat sends OSC to by default.
+pub const DEFAULT_LISTEN_PORT: u16 = 9001;
+/// Sent by VRChat with the ID of the avatar whenever the avatar changes.
+const AVATAR_CHANGE: &str = "/avatar/change";
+/// How often the watcher checks whether it should stop.
+const RECV_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AvatarOptions {
+    /// Follow the avatar VRChat reports, switching mappings and profiles and suspending
+    /// sending to avatars without the parameters.
+    pub enabled: bool,
+    /// Port `/avatar/change` is received on.
+    pub listen_port: u16,
+    /// Mappings used instead of the default one, by avatar ID.
+    pub mappings: BTreeMap<String, Mapping>,
+    /// Profiles applied on switching to an avatar, by avatar ID.
+    pub profiles: BTreeMap<String, String>,
+}
+
+impl Default for AvatarOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_port: DEFAULT_LISTEN_PORT,
+            mappings: Default::default(),
+            profiles: Default::default(),
+        }
+    }
+}
+
+impl AvatarOptions {
+    /// The mapping to send with while `avatar` is worn, or `None` while the avatar has none of
+    /// the parameters of the mapping and sending is suspended. Avatars without an OSC config
+    /// are sent to, as their parameters are unknown.
+    pub fn mapping<'a>(
+        &'a self,
+        default: &'a Mapping,
+        avatar: Option<&Avatar>,
+    ) -> Option<&'a Mapping> {
+        let Some(avatar) = avatar else {
+            return Some(default);
+        };
+        let mapping = self.mappings.get(&avatar.id).unwrap_or(default);
+        let Some(config) = &avatar.config else {
+            return Some(mapping);
+        };
+        config
+            .inputs()
+            .any(|address| mapping.sends_to(address))
+            .then_some(mapping)
+    }
+}
+
+/// The OSC config VRChat writes for each avatar, in
+/// `LocalLow\VRChat\VRChat\OSC\{user ID}\Avatars\{avatar ID}.json`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AvatarConfig {
+    pub id: String,
+    pub name: String,
+    pub parameters: Vec<ConfigParameter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ConfigParameter {
+    pub name: String,
+    /// Where the parameter can be set. Missing for parameters VRChat only reports.
+    pub input: Option<Endpoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Endpoint {
+    pub address: String,
+}
+
+impl AvatarConfig {
+    pub fn load(path: &Path) -> Res<Self> {
+        let json = std::fs::read_to_string(path)?;
+        // VRChat writes the files with a byte order mark
+        Ok(serde_json::from_str(json.trim_start_matches('\u{feff}'))?)
+    }
+
+    /// Finds the config of `avatar_id` among the users in `dir`, the latest written if more
+    /// than one user has worn the avatar.
+    pub fn find(dir: &Path, avatar_id: &str) -> Res<Option<Self>> {
+        let mut latest: Option<(SystemTime, PathBuf)> = None;
+        for user in std::fs::read_dir(dir)? {
+            let path = user?
+                .path()
+                .join("Avatars")
+                .join(format!("{avatar_id}.json"));
+            let Ok(modified) = path.metadata().and_then(|m| m.modified()) else {
+                continue;
+            };
+            if latest.as_ref().is_none_or(|(at, _)| modified > *at) {
+                latest = Some((modified, path));
+            }
+        }
+        latest.map(|(_, path)| Self::load(&path)).transpose()
+    }
+
+    /// The addresses of the parameters that can be set.
+    pub fn inputs(&self) -> impl Iterator<Item = &str> {
+        self.parameters
+            .iter()
+            .filter_map(|p| p.input.as_ref())
+            .map(|input| input.address.as_str())
+    }
+}
+
+/// Where VRChat writes the OSC configs of the avatars.
+pub fn config_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("USERPROFILE")?;
+    Some(PathBuf::from(home).join(r"AppData\LocalLow\VRChat\VRChat\OSC"))
+}
+
+/// An avatar VRChat has switched to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Avatar {
+    pub id: String,
+    /// `None` if VRChat has not written a config for the avatar.
+    pub config: Option<AvatarConfig>,
+    pub config_error: Option<String>,
+}
+
+impl Avatar {
+    pub fn load(id: String, config_dir: Option<&Path>) -> Self {
+        let (config, config_error) = match config_dir.map(|dir| AvatarConfig::find(dir, &id)) {
+            Some(Ok(config)) => (config, None),
+            Some(Err(e)) => (None, Some(e.to_string())),
+            None => (None, None),
+        };
+        Self {
+            id,
+            config,
+            config_error,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.config.as_ref().map_or(&self.id, |c| &c.name)
+    }
+}
+
+/// The ID of the avatar in the last `/avatar/change` message of `packet`.
+fn avatar_change(packet: &OscPacket) -> Option<String> {
+    match packet {
+        OscPacket::Message(message) if message.addr == AVATAR_CHANGE => {
+            match message.args.first() {
+                Some(OscType::String(id)) => Some(id.clone()),
+                _ => None,
+            }
+        }
+        OscPacket::Message(_) => None,
+        OscPacket::Bundle(bundle) => bundle.content.iter().rev().find_map(avatar_change),
+    }
+}
+
+/// Receives the messages VRChat sends on a background thread and keeps the avatar it reports,
+/// until dropped.
+pub struct AvatarWatcher {
+    current: Arc<Mutex<Option<Arc<Avatar>>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AvatarWatcher {
+    pub fn start(port: u16, config_dir: Option<PathBuf>) -> Res<Self> {
+        let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        sock.set_read_timeout(Some(RECV_TIMEOUT))?;
+        let current = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_current, thread_stop) = (current.clone(), stop.clone());
+        let thread = thread::spawn(move || {
+            let mut buf = [0; decoder::MTU];
+            while !thread_stop.load(Ordering::Relaxed) {
+                let Ok(len) = sock.recv(&mut buf) else {
+                    continue;
+                };
+                let Ok((_, packet)) = decoder::decode_udp(&buf[..len]) else {
+                    continue;
+                };
+                if let Some(id) = avatar_change(&packet) {
+                    let avatar = Avatar::load(id, config_dir.as_deref());
+                    *thread_current.lock().unwrap() = Some(Arc::new(avatar));
+                }
+            }
+        });
+        Ok(Self {
+            current,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The avatar reported last, if any has been since the watcher started.
+    pub fn current(&self) -> Option<Arc<Avatar>> {
+        self.current.lock().unwrap().clone()
+    }
+}
+
+impl Drop for AvatarWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Frees the port before a watcher on the same port is started
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What the analyzer knows about the avatar it sends to.
+#[derive(Debug, Default, Clone)]
+pub struct AvatarStatus {
+    pub avatar: Option<Arc<Avatar>>,
+    /// Whether nothing is sent as the avatar has none of the parameters.
+    pub suspended: bool,
+    /// Why the watcher could not start.
+    pub error: Option<String>,
+}
+
+/// Keeps a watcher running while following the avatar is enabled.
+#[derive(Default)]
+pub struct AvatarTracker {
+    /// The port and the watcher started on it. A failed start is not retried until the
+    /// options change.
+    watcher: Option<(u16, Result<AvatarWatcher, String>)>,
+}
+
+impl AvatarTracker {
+    pub fn update(&mut self, options: &AvatarOptions) -> AvatarStatus {
+        if !options.enabled {
+            self.watcher = None;
+            return Default::default();
+        }
+        if self
+            .watcher
+            .as_ref()
+            .is_none_or(|(port, _)| *port != options.listen_port)
+        {
+            self.watcher = None;
+            let watcher = AvatarWatcher::start(options.listen_port, config_dir());
+            self.watcher = Some((options.listen_port, watcher.map_err(|e| e.to_string())));
+        }
+        match &self.watcher {
+            Some((_, Ok(watcher))) => AvatarStatus {
+                avatar: watcher.current(),
+                ..Default::default()
+            },
+            Some((_, Err(e))) => AvatarStatus {
+                error: Some(e.clone()),
+                ..Default::default()
+            },
+            None => Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rosc::{OscBundle, OscMessage};
+
+    const VOICE_ID: &str = "avtr_11111111-2222-3333-4444-555555555555";
+    const PLAIN_ID: &str = "avtr_66666666-7777-8888-9999-000000000000";
+
+    /// The fixtures, laid out the way VRChat does.
+    fn config_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("osc")
+    }
+
+    #[test]
+    fn test_mapping_for_avatar() {
+        let dir = config_dir();
+        let voice = Avatar::load(VOICE_ID.into(), Some(&dir));
+        let plain = Avatar::load(PLAIN_ID.into(), Some(&dir));
+        let unknown = Avatar::load("avtr_unknown".into(), Some(&dir));
+        assert_eq!(voice.name(), "VoiceAnalyzer Test");
+        assert_eq!(plain.name(), "Plain Avatar");
+        assert!(unknown.config.is_none() && unknown.config_error.is_none());
+
+        let default = Mapping::default();
+        let mut options = AvatarOptions::default();
+        assert_eq!(options.mapping(&default, Some(&voice)), Some(&default));
+        // `FT` is only an output of the plain avatar
+        assert_eq!(options.mapping(&default, Some(&plain)), None);
+        assert_eq!(options.mapping(&default, Some(&unknown)), Some(&default));
+
+        let custom = Mapping {
+            rules: default.rules[..1].to_vec(),
+        };
+        options.mappings.insert(VOICE_ID.into(), custom.clone());
+        assert_eq!(options.mapping(&default, Some(&voice)), Some(&custom));
+        options
+            .mappings
+            .insert(VOICE_ID.into(), Mapping { rules: vec![] });
+        assert_eq!(options.mapping(&default, Some(&voice)), None);
+    }
+
+    #[test]
+    fn test_avatar_change() {
+        let message = |addr: &str, id: &str| {
+            OscPacket::Message(OscMessage {
+                addr: addr.into(),
+                args: vec![OscType::String(id.into())],
+            })
+        };
+        assert_eq!(
+            avatar_change(&message(AVATAR_CHANGE, VOICE_ID)),
+            Some(VOICE_ID.into())
+        );
+        let bundle = OscPacket::Bundle(OscBundle {
+            timetag: (0, 1).into(),
+            content: vec![
+                message("/avatar/parameters/VRCEmote", "1"),
+                message(AVATAR_CHANGE, PLAIN_ID),
+            ],
+        });
+        assert_eq!(avatar_change(&bundle), Some(PLAIN_ID.into()));
+        assert_eq!(avatar_change(&message("/avatar/parameters/G1", "")), None);
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::mapping::{Encoding, Mapping, Source};
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Synced bits an avatar can use for expression parameters.
+pub const MAX_SYNCED_BITS: usize = 256;
+/// Fields of animator objects that name a parameter.
+const PARAMETER_FIELDS: [&str; 8] = [
+    "m_TimeParameter",
+    "m_ConditionEvent",
+    "m_BlendParameter",
+    "m_BlendParameterY",
+    "m_DirectBlendParameter",
+    "m_SpeedParameter",
+    "m_MirrorParameter",
+    "m_CycleOffsetParameter",
+];
+
+/// `syncType` of a Modular Avatar parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncType {
+    NotSynced,
+    Int,
+    Float,
+    Bool,
+}
+
+impl SyncType {
+    fn parse(value: &str) -> Self {
+        match value {
+            "1" => Self::Int,
+            "2" => Self::Float,
+            "3" => Self::Bool,
+            _ => Self::NotSynced,
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        match self {
+            Self::NotSynced => 0,
+            Self::Int | Self::Float => 8,
+            Self::Bool => 1,
+        }
+    }
+
+    /// Whether a parameter sent with `encoding` can be synced as this type.
+    fn accepts(&self, encoding: Encoding) -> bool {
+        match self {
+            Self::NotSynced => true,
+            Self::Int => encoding == Encoding::Int,
+            Self::Float => matches!(encoding, Encoding::Float | Encoding::Split),
+            Self::Bool => encoding == Encoding::Bool,
+        }
+    }
+}
+
+/// A parameter of the Modular Avatar Parameters component of a prefab.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub sync: SyncType,
+    pub local_only: bool,
+}
+
+impl Parameter {
+    pub fn bits(&self) -> usize {
+        if self.local_only {
+            0
+        } else {
+            self.sync.bits()
+        }
+    }
+}
+
+/// A prefab with the parameters it adds, and the parameters its animator controller uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub name: String,
+    pub parameters: Vec<Parameter>,
+    pub used: BTreeSet<String>,
+}
+
+impl Feature {
+    pub fn bits(&self) -> usize {
+        self.parameters.iter().map(Parameter::bits).sum()
+    }
+}
+
+/// Reads the parameters of the Modular Avatar Parameters component in a prefab.
+fn parse_prefab(yaml: &str) -> Vec<Parameter> {
+    let mut parameters: Vec<Parameter> = vec![];
+    for line in yaml.lines() {
+        let line = line.trim_start();
+        if let Some(name) = line.strip_prefix("- nameOrPrefix:") {
+            parameters.push(Parameter {
+                name: name.trim().to_owned(),
+                sync: SyncType::NotSynced,
+                local_only: false,
+            });
+            continue;
+        }
+        let Some(parameter) = parameters.last_mut() else {
+            continue;
+        };
+        if let Some(name) = line.strip_prefix("remapTo:").map(str::trim) {
+            if !name.is_empty() {
+                parameter.name = name.to_owned();
+            }
+        } else if let Some(value) = line.strip_prefix("syncType:") {
+            parameter.sync = SyncType::parse(value.trim());
+        } else if let Some(value) = line.strip_prefix("localOnly:") {
+            parameter.local_only = value.trim() == "1";
+        }
+    }
+    parameters
+}
+
+/// The parameters the states, transitions and blend trees of a controller refer to.
+fn parse_controller(yaml: &str) -> BTreeSet<String> {
+    yaml.lines()
+        .filter_map(|line| line.trim_start().split_once(':'))
+        .filter(|(field, _)| PARAMETER_FIELDS.contains(field))
+        .map(|(_, name)| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn find_files(dir: &Path, extension: &str, files: &mut Vec<PathBuf>) -> Res<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, extension, files)?;
+        } else if path.extension().is_some_and(|e| e == extension) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the prefabs with parameters in `dir` and pairs each with the controller of the same
+/// name.
+pub fn load_features(dir: &Path) -> Res<Vec<Feature>> {
+    let (mut prefabs, mut controllers) = (vec![], vec![]);
+    find_files(dir, "prefab", &mut prefabs)?;
+    find_files(dir, "controller", &mut controllers)?;
+    let mut features = vec![];
+    for prefab in prefabs {
+        let parameters = parse_prefab(&std::fs::read_to_string(&prefab)?);
+        if parameters.is_empty() {
+            continue;
+        }
+        let name = prefab.file_stem().unwrap_or_default().to_string_lossy();
+        let mut used = BTreeSet::new();
+        for controller in &controllers {
+            if controller
+                .file_stem()
+                .is_some_and(|s| s.to_string_lossy() == name)
+            {
+                used.extend(parse_controller(&std::fs::read_to_string(controller)?));
+            }
+        }
+        features.push(Feature {
+            name: name.into_owned(),
+            parameters,
+            used,
+        });
+    }
+    features.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(features)
+}
+
+/// The bits of the parameters of `features` together, counting a parameter added by more than
+/// one feature once.
+pub fn combined_bits(features: &[Feature]) -> usize {
+    let mut bits: BTreeMap<&str, usize> = BTreeMap::new();
+    for parameter in features.iter().flat_map(|f| &f.parameters) {
+        let entry = bits.entry(&parameter.name).or_default();
+        *entry = (*entry).max(parameter.bits());
+    }
+    bits.values().sum()
+}
+
+/// The avatar parameters the app sends with the options, with the encoding of each.
+pub fn sent_parameters(
+    mapping: &Mapping,
+    harmonics: usize,
+    send_pitch_range: bool,
+    send_target_note: bool,
+) -> Vec<(String, Encoding)> {
+    mapping
+        .parameters(harmonics)
+        .into_iter()
+        .filter(|(_, rule)| match rule.source {
+            Source::PitchRangeLow | Source::PitchRangeHigh => send_pitch_range,
+            Source::TargetNote => send_target_note,
+            _ => true,
+        })
+        .map(|(name, rule)| (name, rule.encoding))
+        .collect()
+}
+
+/// Synced bits the sent parameters would need if they were all synced.
+pub fn sent_bits(sent: &[(String, Encoding)]) -> usize {
+    sent.iter()
+        .map(|(_, encoding)| match encoding {
+            Encoding::Bool => 1,
+            _ => 8,
+        })
+        .sum()
+}
+
+/// A disagreement between what the app sends and what the assets use.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// Sent by the app but used by none of the animators.
+    Unused { parameter: String },
+    /// Used by the animator of a feature but not sent by the app. Synced bools are left out,
+    /// as they are set from the expression menu.
+    NotSent { parameter: String, feature: String },
+    /// Used by the animator of a feature but synced by none, so only the wearer sees it move.
+    NotSynced { parameter: String, feature: String },
+    /// Sent with an encoding the parameter is not synced as.
+    TypeMismatch {
+        parameter: String,
+        encoding: Encoding,
+        sync: SyncType,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unused { parameter } => {
+                write!(f, "{parameter} is sent but no animator uses it")
+            }
+            Self::NotSent { parameter, feature } => {
+                write!(f, "{parameter} is used by {feature} but is not sent")
+            }
+            Self::NotSynced { parameter, feature } => {
+                write!(f, "{parameter} is used by {feature} but no prefab syncs it")
+            }
+            Self::TypeMismatch {
+                parameter,
+                encoding,
+                sync,
+            } => write!(
+                f,
+                "{parameter} is sent as {encoding:?} but synced as {sync:?}"
+            ),
+        }
+    }
+}
+
+/// Compares what is sent with what the enabled `features` use and sync.
+pub fn check(features: &[Feature], sent: &[(String, Encoding)]) -> Vec<Issue> {
+    let mut synced: BTreeMap<&str, SyncType> = BTreeMap::new();
+    for parameter in features.iter().flat_map(|f| &f.parameters) {
+        if parameter.sync != SyncType::NotSynced {
+            synced.insert(&parameter.name, parameter.sync);
+        }
+    }
+    let sent_names: BTreeSet<&str> = sent.iter().map(|(name, _)| name.as_str()).collect();
+    let mut issues = vec![];
+    for (name, encoding) in sent {
+        if !features.iter().any(|f| f.used.contains(name)) {
+            issues.push(Issue::Unused {
+                parameter: name.clone(),
+            });
+        }
+        if let Some(&sync) = synced.get(name.as_str()) {
+            if !sync.accepts(*encoding) {
+                issues.push(Issue::TypeMismatch {
+                    parameter: name.clone(),
+                    encoding: *encoding,
+                    sync,
+                });
+            }
+        }
+    }
+    for feature in features {
+        for name in &feature.used {
+            let sync = synced.get(name.as_str()).copied();
+            if sync != Some(SyncType::Bool) && !sent_names.contains(name.as_str()) {
+                issues.push(Issue::NotSent {
+                    parameter: name.clone(),
+                    feature: feature.name.clone(),
+                });
+            }
+            if sync.is_none() {
+                issues.push(Issue::NotSynced {
+                    parameter: name.clone(),
+                    feature: feature.name.clone(),
+                });
+            }
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The shipped prefabs and controllers.
+    fn shipped() -> Vec<Feature> {
+        let assets = [
+            (
+                "FormantMonitor",
+                include_str!("../Assets/VoiceAnalyzer/Prefab/FormantMonitor.prefab"),
+                include_str!(
+                    "../Assets/VoiceAnalyzer/Animation/FormantMonitor/FormantMonitor.controller"
+                ),
+            ),
+            (
+                "PitchMonitor",
+                include_str!("../Assets/VoiceAnalyzer/Prefab/PitchMonitor.prefab"),
+                include_str!(
+                    "../Assets/VoiceAnalyzer/Animation/PitchMonitor/PitchMonitor.controller"
+                ),
+            ),
+            (
+                "Spectrogram",
+                include_str!("../Assets/VoiceAnalyzer/Prefab/Spectrogram.prefab"),
+                include_str!(
+                    "../Assets/VoiceAnalyzer/Animation/Spectrogram/Spectrogram.controller"
+                ),
+            ),
+        ];
+        assets
+            .iter()
+            .map(|(name, prefab, controller)| Feature {
+                name: name.to_string(),
+                parameters: parse_prefab(prefab),
+                used: parse_controller(controller),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_budget() {
+        let features = shipped();
+        let bits: Vec<usize> = features.iter().map(Feature::bits).collect();
+        // F1 to F4 split, G1 and 3 bools; FT split, G1 and 3 bools; FT split, 10 gains and 3 bools
+        assert_eq!(bits, [75, 27, 99]);
+        assert_eq!(combined_bits(&features), 169);
+        let sent = sent_parameters(&Mapping::default(), 10, false, false);
+        assert_eq!(sent.len(), 10 + 2 + 8);
+        assert_eq!(sent_bits(&sent), 160);
+    }
+
+    #[test]
+    fn test_check() {
+        let features = shipped();
+        let sent = sent_parameters(&Mapping::default(), 10, true, false);
+        let issues = check(&features, &sent);
+        let unused: Vec<&Issue> = issues
+            .iter()
+            .filter(|i| matches!(i, Issue::Unused { .. }))
+            .collect();
+        assert_eq!(
+            unused,
+            [
+                &Issue::Unused {
+                    parameter: "FT_Min".into()
+                },
+                &Issue::Unused {
+                    parameter: "FT_Max".into()
+                },
+            ]
+        );
+        // G11 to G20 are only synced after adding them to the Spectrogram prefab
+        let not_sent = issues
+            .iter()
+            .filter(|i| matches!(i, Issue::NotSent { .. }))
+            .count();
+        assert_eq!(not_sent, 10);
+        // Without the spectrogram, G2 to G4 of the pitch monitor are not synced
+        let issues = check(&features[..2], &sent);
+        assert!(issues.contains(&Issue::NotSynced {
+            parameter: "G2".into(),
+            feature: "PitchMonitor".into()
+        }));
+
+        let int_ft = [("FT_L".to_owned(), Encoding::Int)];
+        assert!(check(&features, &int_ft).contains(&Issue::TypeMismatch {
+            parameter: "FT_L".into(),
+            encoding: Encoding::Int,
+            sync: SyncType::Float,
+        }));
+    }
+}
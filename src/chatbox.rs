@@ -0,0 +1,255 @@
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::freq_to_midi_note;
+use crate::utils;
+use crate::vowel::VowelSet;
+
+/// VRChat shows at most this many characters in the chatbox.
+const MAX_CHARS: usize = 144;
+/// VRChat ignores chatbox messages sent more often than this.
+const MIN_INTERVAL: f32 = 1.5;
+/// The seconds between messages the UI offers.
+pub const INTERVALS: RangeInclusive<f32> = MIN_INTERVAL..=60.0;
+const DEFAULT_INTERVAL: f32 = 2.0;
+/// Unchanged text is sent again this often, so that the chatbox does not fade out.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_TEMPLATE: &str = "♪ {note} {cents}¢ | F1 {f1} F2 {f2} | {vowel}";
+/// Shown for a value that is not available, such as the pitch while silent.
+const MISSING: &str = "-";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatboxOptions {
+    pub enabled: bool,
+    /// Text with `{field}` replaced by the current value. See `Field` for the names.
+    pub template: String,
+    /// Seconds between messages, at least `MIN_INTERVAL`.
+    pub interval: f32,
+    /// The vowels `{vowel}` is chosen from.
+    pub vowels: VowelSet,
+}
+
+impl Default for ChatboxOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            template: DEFAULT_TEMPLATE.to_owned(),
+            interval: DEFAULT_INTERVAL,
+            vowels: Default::default(),
+        }
+    }
+}
+
+/// A value that can be put in the template.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    /// Nearest note name, such as `A3`.
+    Note,
+    /// Deviation from the nearest note, such as `+12`.
+    Cents,
+    /// Pitch in Hz.
+    Freq,
+    /// Formant in Hz, counted from 1.
+    Formant(usize),
+    /// The closest Japanese vowel.
+    Vowel,
+    /// Input level in dBFS.
+    Volume,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "note" => Self::Note,
+            "cents" => Self::Cents,
+            "freq" => Self::Freq,
+            "f1" => Self::Formant(1),
+            "f2" => Self::Formant(2),
+            "f3" => Self::Formant(3),
+            "f4" => Self::Formant(4),
+            "vowel" => Self::Vowel,
+            "volume" => Self::Volume,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(Field),
+}
+
+/// A parsed chatbox template. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template(Vec<Piece>);
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or("`{` is not closed")?;
+                    let name = rest[..end].trim();
+                    let field = Field::parse(name).ok_or(format!("unknown field: {name}"))?;
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    pieces.push(Piece::Field(field));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err("`}` without `{`".into()),
+                c => text.push(c),
+            }
+        }
+        pieces.push(Piece::Text(text));
+        pieces.retain(|piece| *piece != Piece::Text(String::new()));
+        Ok(Self(pieces))
+    }
+
+    /// Fills in the values, cut to the length the chatbox shows.
+    pub fn render(&self, values: &Values, vowels: VowelSet) -> String {
+        let note = values.freq.map(|f| freq_to_midi_note(&f));
+        let text: String = self
+            .0
+            .iter()
+            .map(|piece| match *piece {
+                Piece::Text(ref text) => text.clone(),
+                Piece::Field(field) => match field {
+                    Field::Note => note.map(|n| utils::midi_note_number_to_str(n.round() as f64)),
+                    Field::Cents => note.map(|n| format!("{:+.0}", (n - n.round()) * 100.0)),
+                    Field::Freq => values.freq.map(|f| format!("{f:.0}")),
+                    Field::Formant(i) => values.formant(i).map(|f| format!("{f:.0}")),
+                    Field::Vowel => values
+                        .formant(1)
+                        .zip(values.formant(2))
+                        .and_then(|(f1, f2)| vowels.classify(f1, f2))
+                        .map(|vowel| vowel.symbol.to_owned()),
+                    Field::Volume => Some(format!("{:.0}", values.volume_db)),
+                }
+                .unwrap_or_else(|| MISSING.to_owned()),
+            })
+            .collect();
+        text.chars().take(MAX_CHARS).collect()
+    }
+}
+
+/// What the analyzer found in the latest frame.
+pub struct Values {
+    pub freq: Option<f32>,
+    pub formants: Vec<f64>,
+    pub volume_db: f32,
+}
+
+impl Values {
+    /// Formants are only meaningful while the voice has a pitch.
+    fn formant(&self, i: usize) -> Option<f64> {
+        self.freq?;
+        self.formants.get(i - 1).copied()
+    }
+}
+
+/// Decides when a chatbox message is due and what it says.
+#[derive(Default)]
+pub struct Chatbox {
+    template: Option<(String, Result<Template, String>)>,
+    sent_at: Option<Instant>,
+    last_text: String,
+}
+
+impl Chatbox {
+    /// The text to send now, if any.
+    pub fn update(
+        &mut self,
+        options: &ChatboxOptions,
+        values: &Values,
+        now: Instant,
+    ) -> Option<String> {
+        // `max` before `min` also turns NaN into the shortest interval
+        let interval = options.interval.max(MIN_INTERVAL).min(*INTERVALS.end());
+        let interval = Duration::from_secs_f32(interval);
+        if self
+            .sent_at
+            .is_some_and(|at| now.duration_since(at) < interval)
+        {
+            return None;
+        }
+        if self
+            .template
+            .as_ref()
+            .is_none_or(|t| t.0 != options.template)
+        {
+            let parsed = Template::parse(&options.template);
+            self.template = Some((options.template.clone(), parsed));
+        }
+        let Some((_, Ok(template))) = &self.template else {
+            return None;
+        };
+        let text = template.render(values, options.vowels);
+        let stale = self
+            .sent_at
+            .is_none_or(|at| now.duration_since(at) >= REFRESH_INTERVAL);
+        if text == self.last_text && !stale {
+            return None;
+        }
+        self.sent_at = Some(now);
+        self.last_text.clone_from(&text);
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let template = Template::parse("♪ {note} {cents}¢ | {{F1}} {f1} | {vowel}").unwrap();
+        let values = Values {
+            freq: Some(222.0),
+            formants: vec![310.0, 2150.0],
+            volume_db: -20.0,
+        };
+        let text = template.render(&values, VowelSet::Male);
+        assert_eq!(text, "♪ A3 +16¢ | {F1} 310 | い");
+        let silent = Values {
+            freq: None,
+            ..values
+        };
+        let text = template.render(&silent, VowelSet::Male);
+        assert_eq!(text, "♪ - -¢ | {F1} - | -");
+        assert!(Template::parse("{pitch}").is_err());
+        assert!(Template::parse("{note").is_err());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut chatbox = Chatbox::default();
+        let options = ChatboxOptions::default();
+        let values = Values {
+            freq: Some(440.0),
+            formants: vec![],
+            volume_db: -20.0,
+        };
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs_f32(seconds);
+        assert!(chatbox.update(&options, &values, at(0.0)).is_some());
+        assert!(chatbox.update(&options, &values, at(1.0)).is_none());
+        // Unchanged text is not sent again until it is refreshed
+        assert!(chatbox.update(&options, &values, at(3.0)).is_none());
+        assert!(chatbox.update(&options, &values, at(10.0)).is_some());
+    }
+}
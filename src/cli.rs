@@ -0,0 +1,261 @@
+use std::path::PathBuf;
+
+use crate::budget;
+use crate::mapping::Mapping;
+use crate::settings::Settings;
+use crate::unity::{self, AssetOptions};
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Command line options.
+#[derive(Debug, Default)]
+pub struct Args {
+    /// Name of a profile, or path to an exported profile file, to switch to on startup.
+    pub profile: Option<String>,
+    /// Run instead of the GUI.
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `generate-assets <dir> [--harmonics N] [--mapping FILE] [--name NAME] [--path PATH]`
+    /// writes the spectrogram clips and controller to `dir`. The parameters follow the saved
+    /// settings unless overridden, so that they match what is sent.
+    GenerateAssets {
+        dir: PathBuf,
+        harmonics: Option<usize>,
+        mapping: Option<PathBuf>,
+        name: Option<String>,
+        path: Option<String>,
+    },
+    /// `check-assets [dir] [--harmonics N] [--mapping FILE] [--features A,B]` prints the synced
+    /// bits of the prefabs in `dir` and where they disagree with what is sent. What is sent
+    /// follows the saved settings unless overridden.
+    CheckAssets {
+        dir: PathBuf,
+        harmonics: Option<usize>,
+        mapping: Option<PathBuf>,
+        features: Option<Vec<String>>,
+    },
+}
+
+impl Command {
+    pub fn run(&self) -> Res<()> {
+        match self {
+            Self::GenerateAssets {
+                dir,
+                harmonics,
+                mapping,
+                name,
+                path,
+            } => {
+                let settings = Settings::load()?;
+                let defaults = AssetOptions::default();
+                let options = AssetOptions {
+                    harmonics: harmonics.unwrap_or(settings.analyzer.harmonics),
+                    mapping: match mapping {
+                        Some(path) => Mapping::import(path)?,
+                        None => settings.osc.mapping,
+                    },
+                    name: name.clone().unwrap_or(defaults.name),
+                    path: path.clone().unwrap_or(defaults.path),
+                };
+                let missing = unity::missing_properties(&options)?;
+                if !missing.is_empty() {
+                    eprintln!(
+                        "warning: the spectrogram shader has no {}, so their clips will not move",
+                        missing.join(", ")
+                    );
+                }
+                let assets = unity::generate(&options)?;
+                unity::write(dir, &assets)?;
+                println!("{} files written to {}", assets.len(), dir.display());
+                Ok(())
+            }
+            Self::CheckAssets {
+                dir,
+                harmonics,
+                mapping,
+                features,
+            } => {
+                let settings = Settings::load()?;
+                let mut found = budget::load_features(dir)?;
+                if let Some(names) = features {
+                    if let Some(name) = names.iter().find(|n| !found.iter().any(|f| &f.name == *n))
+                    {
+                        return Err(format!("no prefab named {name} in {}", dir.display()).into());
+                    }
+                    found.retain(|f| names.contains(&f.name));
+                }
+                let mapping = match mapping {
+                    Some(path) => Mapping::import(path)?,
+                    None => settings.osc.mapping,
+                };
+                let sent = budget::sent_parameters(
+                    &mapping,
+                    harmonics.unwrap_or(settings.analyzer.harmonics),
+                    settings.analyzer.send_pitch_range,
+                    settings.osc.send_target_note,
+                );
+                for feature in &found {
+                    println!("{}: {} bits", feature.name, feature.bits());
+                }
+                println!(
+                    "Together: {} / {} bits",
+                    budget::combined_bits(&found),
+                    budget::MAX_SYNCED_BITS
+                );
+                println!(
+                    "Sent: {} parameters, {} bits if all synced",
+                    sent.len(),
+                    budget::sent_bits(&sent)
+                );
+                let issues = budget::check(&found, &sent);
+                if issues.is_empty() {
+                    println!("No issues found");
+                }
+                for issue in issues {
+                    println!("- {issue}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Release builds have no console of their own, so what is printed goes nowhere unless the
+/// console of the terminal the app was started from is attached.
+#[cfg(windows)]
+pub fn attach_console() {
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    // Fails if the parent has no console or one is already attached, which are both fine
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+pub fn attach_console() {}
+
+impl Args {
+    pub fn parse() -> Res<Self> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from(args: impl IntoIterator<Item = String>) -> Res<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter().peekable();
+        match args.peek().map(String::as_str) {
+            Some("generate-assets") => {
+                args.next();
+                parsed.command = Some(parse_generate_assets(args)?);
+                return Ok(parsed);
+            }
+            Some("check-assets") => {
+                args.next();
+                parsed.command = Some(parse_check_assets(args)?);
+                return Ok(parsed);
+            }
+            _ => {}
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--profile" | "-p" => {
+                    let profile = args.next().ok_or("--profile requires a name or a file")?;
+                    parsed.profile = Some(profile);
+                }
+                _ => return Err(format!("unknown argument: {arg}").into()),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn parse_generate_assets(mut args: impl Iterator<Item = String>) -> Res<Command> {
+    let mut dir = None;
+    let (mut harmonics, mut mapping, mut name, mut path) = (None, None, None, None);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} requires a value"));
+        match arg.as_str() {
+            "--harmonics" => harmonics = Some(value()?.parse()?),
+            "--mapping" => mapping = Some(PathBuf::from(value()?)),
+            "--name" => name = Some(value()?),
+            "--path" => path = Some(value()?),
+            _ if !arg.starts_with('-') && dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unknown argument: {arg}").into()),
+        }
+    }
+    Ok(Command::GenerateAssets {
+        dir: dir.ok_or("generate-assets requires an output directory")?,
+        harmonics,
+        mapping,
+        name,
+        path,
+    })
+}
+
+fn parse_check_assets(mut args: impl Iterator<Item = String>) -> Res<Command> {
+    let mut dir = None;
+    let (mut harmonics, mut mapping, mut features) = (None, None, None);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} requires a value"));
+        match arg.as_str() {
+            "--harmonics" => harmonics = Some(value()?.parse()?),
+            "--mapping" => mapping = Some(PathBuf::from(value()?)),
+            "--features" => features = Some(value()?.split(',').map(str::to_owned).collect()),
+            _ if !arg.starts_with('-') && dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unknown argument: {arg}").into()),
+        }
+    }
+    Ok(Command::CheckAssets {
+        dir: dir.unwrap_or_else(|| "Assets".into()),
+        harmonics,
+        mapping,
+        features,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Res<Args> {
+        Args::parse_from(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(&["-p", "Male"]).unwrap().profile.as_deref(),
+            Some("Male")
+        );
+        let args = parse(&["generate-assets", "out", "--harmonics", "32"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::GenerateAssets {
+                dir: "out".into(),
+                harmonics: Some(32),
+                mapping: None,
+                name: None,
+                path: None,
+            })
+        );
+        assert!(parse(&["generate-assets"]).is_err());
+        assert!(parse(&["generate-assets", "out", "--harmonics"]).is_err());
+        let args = parse(&["check-assets", "--features", "PitchMonitor,Spectrogram"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::CheckAssets {
+                dir: "Assets".into(),
+                harmonics: None,
+                mapping: None,
+                features: Some(vec!["PitchMonitor".into(), "Spectrogram".into()]),
+            })
+        );
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
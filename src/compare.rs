@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{Analyzer, AnalyzerOptions, Results, CHUNK_SIZE};
+use crate::sound_device::{Capturer, ChannelMode, DeviceError, DeviceList};
+use crate::utils;
+
+/// Where the second voice of the comparison mode comes from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CompareSource {
+    /// A device, which may be the main device with another channel.
+    Device {
+        id: String,
+        channel_mode: ChannelMode,
+    },
+    /// A WAV recording, such as a teacher's reference.
+    File(PathBuf),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompareOptions {
+    pub enabled: bool,
+    pub source: Option<CompareSource>,
+}
+
+impl CompareSource {
+    /// Starts an analyzer for the comparison voice. It analyzes with the main options but
+    /// does not send OSC.
+    pub fn start(
+        &self,
+        devices: &DeviceList,
+        options: AnalyzerOptions,
+    ) -> Result<Analyzer, DeviceError> {
+        let (capturer, channel_mode) = match self {
+            Self::Device { id, channel_mode } => {
+                let device = devices
+                    .devices
+                    .iter()
+                    .find(|device| device.id == *id && device.connected)
+                    .ok_or_else(|| DeviceError::NotFound(id.clone()))?;
+                (device.capturer(CHUNK_SIZE), *channel_mode)
+            }
+            Self::File(path) => (Capturer::from_file(path, CHUNK_SIZE)?, ChannelMode::Sum),
+        };
+        let options = AnalyzerOptions {
+            channel_mode,
+            ..options
+        };
+        Ok(Analyzer::new(capturer, options, None, None))
+    }
+}
+
+/// How far the main voice is from the comparison voice in the latest frame.
+pub struct Difference {
+    pub cents: Option<f32>,
+    pub hz: Option<f32>,
+    pub f1: Option<f64>,
+    pub f2: Option<f64>,
+}
+
+impl Difference {
+    pub fn new(main: &Results, comparison: &Results) -> Self {
+        let pair = main.freq().zip(comparison.freq());
+        let (peaks, reference_peaks) = (main.formant_peak(), comparison.formant_peak());
+        let formant_diff = |i: usize| -> Option<f64> {
+            pair?;
+            Some(peaks.get(i)? - reference_peaks.get(i)?)
+        };
+        Self {
+            cents: pair.map(|(f, r)| utils::cents(f, r)),
+            hz: pair.map(|(f, r)| f - r),
+            f1: formant_diff(0),
+            f2: formant_diff(1),
+        }
+    }
+}
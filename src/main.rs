@@ -2,11 +2,41 @@
 
 mod analyzer;
 mod app;
+mod avatar;
+mod budget;
+mod chatbox;
+mod cli;
+mod compare;
+mod mapping;
+mod melody;
 mod osc;
+mod osc_recording;
+mod overlay;
+mod profile;
+mod session;
+mod settings;
+mod smoothing;
 mod sound_device;
+mod spectrogram;
+mod transcribe;
+mod unity;
 mod utils;
+mod vowel;
 
 fn main() -> eframe::Result {
+    let args = cli::Args::parse().unwrap_or_else(|e| {
+        cli::attach_console();
+        eprintln!("{e}");
+        Default::default()
+    });
+    if let Some(command) = &args.command {
+        cli::attach_console();
+        if let Err(e) = command.run() {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     wasapi::initialize_mta().unwrap();
 
     let options = eframe::NativeOptions {
@@ -18,6 +48,6 @@ fn main() -> eframe::Result {
     eframe::run_native(
         "Voice Analyzer",
         options,
-        Box::new(|cc| Ok(Box::new(app::App::new(cc)))),
+        Box::new(|cc| Ok(Box::new(app::App::new(cc, args)))),
     )
 }
@@ -0,0 +1,496 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{freq_to_midi_note, PitchRange};
+use crate::utils;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Where VRChat receives the parameters of the avatar.
+pub const PARAMETER_PREFIX: &str = "/avatar/parameters/";
+/// Replaced by the harmonic number in the address of an `each_gain` rule.
+const HARMONIC_PLACEHOLDER: &str = "{k}";
+/// Formants are sent up to this frequency in Hz.
+const MAX_FORMANT_FREQ: f32 = 8192.0;
+
+/// A value the analyzer provides.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Pitch in Hz.
+    F0,
+    /// Pitch as a MIDI note number with a fraction.
+    MidiNote,
+    /// Pitch normalized to 0..1 over the pitch range of the analyzer options.
+    Pitch,
+    /// 1 while a pitch is detected, 0 otherwise.
+    Voiced,
+    /// Input level in dBFS.
+    RmsDb,
+    /// Gain of the k-th harmonic, counted from 1, normalized to 0..1.
+    Gain(usize),
+    /// The gain of every harmonic, each sent to the address with `{k}` replaced.
+    EachGain,
+    /// The n-th formant in Hz, counted from 1.
+    Formant(usize),
+    /// The lowest and highest notes of the pitch range, available when sending it is enabled.
+    PitchRangeLow,
+    PitchRangeHigh,
+    /// The note of the reference melody normalized like `pitch`, or -1 between notes.
+    /// Available when sending it is enabled.
+    TargetNote,
+}
+
+/// The options that smooth and filter a group of parameters apply to a rule with the source.
+/// They are tuned for values in 0..1, so a rule is only put in the group of its source if its
+/// output is normalized, by the source itself or by a `range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Pitch,
+    Gains,
+    Formants,
+    Other,
+}
+
+impl Source {
+    pub fn group(&self) -> Group {
+        match self {
+            Self::F0 | Self::MidiNote | Self::Pitch => Group::Pitch,
+            Self::Gain(_) | Self::EachGain => Group::Gains,
+            Self::Formant(_) => Group::Formants,
+            _ => Group::Other,
+        }
+    }
+
+    /// Whether the values are already in 0..1.
+    fn is_normalized(&self) -> bool {
+        matches!(
+            self,
+            Self::Pitch | Self::Voiced | Self::Gain(_) | Self::EachGain | Self::TargetNote
+        )
+    }
+}
+
+/// Applied to the value of the source in the order of the fields.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    /// Values outside [min, max] are moved to the nearest end.
+    pub clamp: Option<[f32; 2]>,
+    /// Maps [from, to] linearly to [0, 1].
+    pub range: Option<[f32; 2]>,
+    /// Raises the value to this power, keeping the sign. 1 keeps it linear.
+    pub curve: f32,
+    /// Replaces the value `x` with `1 - x`.
+    pub invert: bool,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            clamp: None,
+            range: None,
+            curve: 1.0,
+            invert: false,
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, mut x: f32) -> f32 {
+        if let Some([min, max]) = self.clamp {
+            x = x.clamp(min.min(max), max.max(min));
+        }
+        if let Some([from, to]) = self.range {
+            x = utils::normalize(x, from, to);
+        }
+        if self.curve != 1.0 {
+            x = x.signum() * x.abs().powf(self.curve);
+        }
+        if self.invert {
+            x = 1.0 - x;
+        }
+        x
+    }
+}
+
+/// How a value is put in OSC messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Float,
+    /// Rounded to the nearest integer.
+    Int,
+    /// True above 0.5.
+    Bool,
+    /// A value in 0..1 as two floats of 7 bits each, sent to the address with `_L` and `_H`
+    /// appended, for more precision than a synced float parameter has.
+    Split,
+}
+
+/// Sends the value of `source` to `address`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub source: Source,
+    #[serde(default)]
+    pub transform: Transform,
+    #[serde(default)]
+    pub encoding: Encoding,
+    pub address: String,
+    /// Sent instead while the source has no value, such as the pitch while silent. Nothing
+    /// is sent then if not given.
+    #[serde(default)]
+    pub missing: Option<f32>,
+}
+
+impl Rule {
+    /// The group of the source for normalized outputs, `Group::Other` for raw values such as
+    /// Hz, which the smoothing of the group would not suit.
+    pub fn group(&self) -> Group {
+        if self.source.is_normalized() || self.transform.range.is_some() {
+            self.source.group()
+        } else {
+            Group::Other
+        }
+    }
+
+    fn new(source: Source, encoding: Encoding, address: &str) -> Self {
+        Self {
+            source,
+            transform: Default::default(),
+            encoding,
+            address: address.to_owned(),
+            missing: None,
+        }
+    }
+
+    /// Whether the rule sends a message to `address`.
+    fn sends_to(&self, address: &str) -> bool {
+        let base = match self.encoding {
+            Encoding::Split => address
+                .strip_suffix("_L")
+                .or_else(|| address.strip_suffix("_H")),
+            _ => Some(address),
+        };
+        let Some(base) = base else {
+            return false;
+        };
+        if self.source != Source::EachGain {
+            return base == self.address;
+        }
+        let Some((prefix, suffix)) = self.address.split_once(HARMONIC_PLACEHOLDER) else {
+            return false;
+        };
+        base.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .is_some_and(|k| !k.is_empty() && k.bytes().all(|b| b.is_ascii_digit()))
+    }
+}
+
+/// What the analyzer found in a frame, as far as it can be sent.
+pub struct Values {
+    pub freq: Option<f32>,
+    pub rms: f32,
+    pub gains: Vec<f32>,
+    pub formants: Vec<f64>,
+    pub pitch_range: PitchRange,
+    pub send_pitch_range: bool,
+    pub target_note: Option<f32>,
+}
+
+impl Values {
+    fn get(&self, source: Source) -> Option<f32> {
+        match source {
+            Source::F0 => self.freq,
+            Source::MidiNote => self.freq.map(|f| freq_to_midi_note(&f)),
+            Source::Pitch => self.freq.map(|f| self.pitch_range.normalize(f)),
+            Source::Voiced => Some(if self.freq.is_some() { 1.0 } else { 0.0 }),
+            Source::RmsDb => Some(utils::to_db(self.rms)),
+            Source::Gain(k) => self.gains.get(k.checked_sub(1)?).copied(),
+            Source::EachGain => None,
+            Source::Formant(n) => self.formants.get(n.checked_sub(1)?).map(|&f| f as f32),
+            Source::PitchRangeLow => self.send_pitch_range.then_some(self.pitch_range.low as f32),
+            Source::PitchRangeHigh => self
+                .send_pitch_range
+                .then_some(self.pitch_range.high as f32),
+            Source::TargetNote => self.target_note,
+        }
+    }
+}
+
+/// A value ready to be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub address: String,
+    pub value: f32,
+    pub encoding: Encoding,
+    pub group: Group,
+    /// Whether `value` stands in for a missing source value.
+    pub missing: bool,
+}
+
+/// The rules that turn the analysis of a frame into OSC parameters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Mapping {
+    /// The parameters the VoiceAnalyzer prefabs use.
+    fn default() -> Self {
+        let formant = |n| Rule {
+            transform: Transform {
+                clamp: Some([0.0, MAX_FORMANT_FREQ]),
+                // The split parameters hold 14 bits
+                range: Some([0.0, 0x3FFF as f32]),
+                ..Default::default()
+            },
+            ..Rule::new(
+                Source::Formant(n),
+                Encoding::Split,
+                &format!("/avatar/parameters/F{n}"),
+            )
+        };
+        let rules = vec![
+            Rule::new(Source::EachGain, Encoding::Float, "/avatar/parameters/G{k}"),
+            Rule {
+                missing: Some(-1.0),
+                ..Rule::new(Source::Pitch, Encoding::Split, "/avatar/parameters/FT")
+            },
+            formant(1),
+            formant(2),
+            formant(3),
+            formant(4),
+            Rule::new(
+                Source::PitchRangeLow,
+                Encoding::Int,
+                "/avatar/parameters/FT_Min",
+            ),
+            Rule::new(
+                Source::PitchRangeHigh,
+                Encoding::Int,
+                "/avatar/parameters/FT_Max",
+            ),
+            Rule::new(
+                Source::TargetNote,
+                Encoding::Float,
+                "/avatar/parameters/FT_Target",
+            ),
+        ];
+        Self { rules }
+    }
+}
+
+impl Mapping {
+    pub fn import(path: &Path) -> Res<Self> {
+        let mapping: Self = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    pub fn export(&self, path: &Path) -> Res<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Res<()> {
+        for rule in &self.rules {
+            if !rule.address.starts_with('/') {
+                return Err(format!("address must start with `/`: {}", rule.address).into());
+            }
+            let transform = &rule.transform;
+            let bounds = transform.clamp.into_iter().chain(transform.range).flatten();
+            if bounds.chain(rule.missing).any(|v| !v.is_finite()) {
+                return Err(format!("values must be finite: {}", rule.address).into());
+            }
+            if transform.range.is_some_and(|[from, to]| from == to) {
+                return Err(format!("range must not be empty: {}", rule.address).into());
+            }
+            if !(transform.curve > 0.0 && transform.curve.is_finite()) {
+                return Err(format!("curve must be positive: {}", rule.address).into());
+            }
+            let has_placeholder = rule.address.contains(HARMONIC_PLACEHOLDER);
+            if (rule.source == Source::EachGain) != has_placeholder {
+                let message = "`{k}` is used in the address of each_gain rules and only there";
+                return Err(format!("{message}: {}", rule.address).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of the avatar parameters sent with `harmonics` harmonics, each with the rule
+    /// it is sent by. Rules sending elsewhere than to avatar parameters are left out.
+    pub fn parameters(&self, harmonics: usize) -> Vec<(String, &Rule)> {
+        let mut parameters = vec![];
+        for rule in &self.rules {
+            let Some(name) = rule.address.strip_prefix(PARAMETER_PREFIX) else {
+                continue;
+            };
+            let names: Vec<String> = if rule.source == Source::EachGain {
+                (1..=harmonics)
+                    .map(|k| name.replace(HARMONIC_PLACEHOLDER, &k.to_string()))
+                    .collect()
+            } else {
+                vec![name.to_owned()]
+            };
+            for name in names {
+                if rule.encoding == Encoding::Split {
+                    parameters.push((format!("{name}_L"), rule));
+                    parameters.push((format!("{name}_H"), rule));
+                } else {
+                    parameters.push((name, rule));
+                }
+            }
+        }
+        parameters
+    }
+
+    /// Whether any rule sends a message to `address`.
+    pub fn sends_to(&self, address: &str) -> bool {
+        self.rules.iter().any(|rule| rule.sends_to(address))
+    }
+
+    pub fn evaluate(&self, values: &Values) -> Vec<Output> {
+        let mut outputs = vec![];
+        for rule in &self.rules {
+            let mut push = |address: String, value: Option<f32>| {
+                // Such as `rms_db` in silence, which VRChat cannot use
+                let value = value
+                    .map(|v| rule.transform.apply(v))
+                    .filter(|v| v.is_finite());
+                let (value, missing) = match value {
+                    Some(value) => (value, false),
+                    None => match rule.missing {
+                        Some(value) => (value, true),
+                        None => return,
+                    },
+                };
+                outputs.push(Output {
+                    address,
+                    value,
+                    encoding: rule.encoding,
+                    group: rule.group(),
+                    missing,
+                });
+            };
+            if rule.source == Source::EachGain {
+                for (i, &gain) in values.gains.iter().enumerate() {
+                    let address = rule
+                        .address
+                        .replace(HARMONIC_PLACEHOLDER, &(i + 1).to_string());
+                    push(address, Some(gain));
+                }
+            } else {
+                push(rule.address.clone(), values.get(rule.source));
+            }
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values() -> Values {
+        Values {
+            freq: None,
+            rms: 0.1,
+            gains: vec![0.5, 0.25],
+            formants: vec![700.0, 9000.0],
+            pitch_range: PitchRange::new(40, 79),
+            send_pitch_range: true,
+            target_note: None,
+        }
+    }
+
+    #[test]
+    fn test_default_mapping() {
+        let outputs = Mapping::default().evaluate(&values());
+        let sent: Vec<(&str, f32)> = outputs
+            .iter()
+            .map(|o| (o.address.as_str(), o.value))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                ("/avatar/parameters/G1", 0.5),
+                ("/avatar/parameters/G2", 0.25),
+                ("/avatar/parameters/FT", -1.0),
+                ("/avatar/parameters/F1", 700.0 / 0x3FFF as f32),
+                ("/avatar/parameters/F2", 8192.0 / 0x3FFF as f32),
+                ("/avatar/parameters/FT_Min", 40.0),
+                ("/avatar/parameters/FT_Max", 79.0),
+            ]
+        );
+        assert!(outputs[2].missing);
+
+        let names: Vec<String> = Mapping::default()
+            .parameters(2)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names[..4], ["G1", "G2", "FT_L", "FT_H"]);
+        assert_eq!(names.len(), 2 + 2 + 4 * 2 + 3);
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let json = r#"{ "rules": [
+            { "source": "rms_db", "transform": { "clamp": [-60, 0], "range": [-60, 0],
+              "curve": 2, "invert": true }, "address": "/avatar/parameters/Quiet" },
+            { "source": "voiced", "encoding": "bool", "address": "/avatar/parameters/Voiced" },
+            { "source": { "gain": 2 }, "encoding": "int", "address": "/avatar/parameters/G" }
+        ] }"#;
+        let mapping: Mapping = serde_json::from_str(json).unwrap();
+        mapping.validate().unwrap();
+        let outputs = mapping.evaluate(&values());
+        let quiet = 1.0 - (40.0f32 / 60.0).powi(2);
+        assert!((outputs[0].value - quiet).abs() < 1e-5);
+        assert_eq!(outputs[1].value, 0.0);
+        assert_eq!(outputs[2].encoding, Encoding::Int);
+
+        let each_gain = Mapping {
+            rules: vec![Rule::new(Source::EachGain, Encoding::Float, "/G")],
+        };
+        assert!(each_gain.validate().is_err());
+        for transform in [
+            r#"{ "range": [100, 100] }"#,
+            r#"{ "curve": 0 }"#,
+            r#"{ "curve": -1 }"#,
+        ] {
+            let json = format!(
+                r#"{{ "rules": [ {{ "source": "f0", "transform": {transform},
+                "address": "/avatar/parameters/F" }} ] }}"#
+            );
+            let mapping: Mapping = serde_json::from_str(&json).unwrap();
+            assert!(mapping.validate().is_err(), "{transform}");
+        }
+    }
+
+    #[test]
+    fn test_raw_values() {
+        let json = r#"{ "rules": [
+            { "source": "rms_db", "address": "/avatar/parameters/Db" },
+            { "source": "rms_db", "address": "/avatar/parameters/Quiet", "missing": 0 },
+            { "source": "f0", "address": "/avatar/parameters/Hz", "missing": 0 },
+            { "source": "f0", "transform": { "range": [80, 800] },
+              "address": "/avatar/parameters/Pitch", "missing": 0 }
+        ] }"#;
+        let mapping: Mapping = serde_json::from_str(json).unwrap();
+        mapping.validate().unwrap();
+        let silent = Values {
+            rms: 0.0,
+            ..values()
+        };
+        let outputs = mapping.evaluate(&silent);
+        // -inf dB is not sent, or replaced by `missing`
+        assert_eq!(outputs[0].address, "/avatar/parameters/Quiet");
+        assert!(outputs[0].missing);
+        // Smoothing for the pitch is only applied to normalized values
+        assert_eq!(outputs[1].group, Group::Other);
+        assert_eq!(outputs[2].group, Group::Pitch);
+    }
+}
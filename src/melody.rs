@@ -0,0 +1,406 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use roxmltree::Node;
+
+use crate::analyzer::freq_to_midi_note;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// A frame within this many cents of the target note counts as in tune.
+pub const IN_TUNE_CENTS: f32 = 50.0;
+/// MIDI channel 10 carries drums, which are not part of the melody.
+const DRUM_CHANNEL: u8 = 9;
+const DEFAULT_SECONDS_PER_BEAT: f64 = 0.5;
+
+/// A note of the reference melody, timed in seconds from the start cue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub start: f64,
+    pub end: f64,
+    pub key: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct Melody {
+    /// Sorted by start time.
+    pub notes: Vec<Note>,
+}
+
+/// Converts positions in beats to seconds.
+struct TempoMap {
+    /// (beat, seconds per beat) of each tempo change, sorted by beat.
+    changes: Vec<(f64, f64)>,
+}
+
+impl TempoMap {
+    fn new(mut changes: Vec<(f64, f64)>) -> Self {
+        changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if changes.first().is_none_or(|&(beat, _)| beat > 0.0) {
+            changes.insert(0, (0.0, DEFAULT_SECONDS_PER_BEAT));
+        }
+        Self { changes }
+    }
+
+    fn seconds_at(&self, beat: f64) -> f64 {
+        let mut seconds = 0.0;
+        for (i, &(start, seconds_per_beat)) in self.changes.iter().enumerate() {
+            let end = self.changes.get(i + 1).map_or(f64::INFINITY, |c| c.0);
+            seconds += (beat.min(end) - start).max(0.0) * seconds_per_beat;
+        }
+        seconds
+    }
+}
+
+impl Melody {
+    /// Loads a Standard MIDI File (`.mid`, `.midi`) or an uncompressed MusicXML file.
+    pub fn load(path: &Path) -> Res<Self> {
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("mid" | "midi") => Self::from_midi(&std::fs::read(path)?),
+            Some("musicxml" | "xml") => Self::from_musicxml(&std::fs::read_to_string(path)?),
+            _ => Err("unsupported file type".into()),
+        }
+    }
+
+    pub fn from_midi(bytes: &[u8]) -> Res<Self> {
+        let smf = Smf::parse(bytes)?;
+        // Either would make every note time infinite or NaN
+        let (ticks_per_beat, mut tempos) = match smf.header.timing {
+            Timing::Metrical(ticks) if ticks == 0 => return Err("zero ticks per beat".into()),
+            Timing::Timecode(_, 0) => return Err("zero subframes per frame".into()),
+            Timing::Metrical(ticks) => (ticks.as_int() as f64, vec![]),
+            // Treat a tick as a beat of fixed length
+            Timing::Timecode(fps, subframes) => {
+                let tick = 1.0 / (fps.as_f32() as f64 * subframes as f64);
+                (1.0, vec![(0.0, tick)])
+            }
+        };
+        let mut notes = vec![];
+        for track in &smf.tracks {
+            let mut tick = 0;
+            let mut pressed: Vec<(u8, u8, u64)> = vec![];
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                let beat = |tick: u64| tick as f64 / ticks_per_beat;
+                match event.kind {
+                    TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                        if let Timing::Metrical(_) = smf.header.timing {
+                            tempos.push((beat(tick), tempo.as_int() as f64 / 1e6));
+                        }
+                    }
+                    TrackEventKind::Midi { channel, message } if channel != DRUM_CHANNEL => {
+                        let channel = channel.as_int();
+                        let (key, on) = match message {
+                            MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int() > 0),
+                            MidiMessage::NoteOff { key, .. } => (key.as_int(), false),
+                            _ => continue,
+                        };
+                        if on {
+                            pressed.push((channel, key, tick));
+                        } else if let Some(i) =
+                            pressed.iter().position(|p| p.0 == channel && p.1 == key)
+                        {
+                            let (_, key, start) = pressed.remove(i);
+                            notes.push((beat(start), beat(tick), key));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self::from_beats(notes, TempoMap::new(tempos)))
+    }
+
+    /// Reads the first part of a MusicXML score.
+    pub fn from_musicxml(text: &str) -> Res<Self> {
+        let doc = roxmltree::Document::parse(text)?;
+        let part = doc
+            .descendants()
+            .find(|n| n.has_tag_name("part"))
+            .ok_or("no part in the score")?;
+        let mut notes: Vec<(f64, f64, u8)> = vec![];
+        let mut tempos = vec![];
+        let mut divisions = 1.0;
+        let mut position = 0.0;
+        let mut last_start = 0.0;
+        let elements = part
+            .children()
+            .filter(|n| n.has_tag_name("measure"))
+            .flat_map(|measure| measure.children().filter(Node::is_element));
+        for element in elements {
+            let duration = child_text(element, "duration").unwrap_or(0.0) / divisions;
+            match element.tag_name().name() {
+                "attributes" => {
+                    divisions = child_text(element, "divisions").unwrap_or(divisions);
+                }
+                "direction" | "sound" => {
+                    let tempo = element
+                        .descendants()
+                        .find(|n| n.has_tag_name("sound"))
+                        .and_then(|sound| sound.attribute("tempo")?.parse::<f64>().ok());
+                    if let Some(tempo) = tempo.filter(|&t| t > 0.0) {
+                        tempos.push((position, 60.0 / tempo));
+                    }
+                }
+                "backup" => position -= duration,
+                "forward" => position += duration,
+                "note" => {
+                    let is_chord = element.children().any(|n| n.has_tag_name("chord"));
+                    let start = if is_chord { last_start } else { position };
+                    if let Some(key) = note_key(element) {
+                        let tie_stop = element
+                            .children()
+                            .any(|n| n.has_tag_name("tie") && n.attribute("type") == Some("stop"));
+                        let tied = notes
+                            .iter_mut()
+                            .rev()
+                            .find(|n| tie_stop && n.2 == key && (n.1 - start).abs() < 1e-6);
+                        match tied {
+                            Some(note) => note.1 = start + duration,
+                            None if duration > 0.0 => notes.push((start, start + duration, key)),
+                            None => {}
+                        }
+                    }
+                    if !is_chord {
+                        last_start = position;
+                        position += duration;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self::from_beats(notes, TempoMap::new(tempos)))
+    }
+
+    fn from_beats(notes: Vec<(f64, f64, u8)>, tempo_map: TempoMap) -> Self {
+        let mut notes: Vec<Note> = notes
+            .into_iter()
+            .map(|(start, end, key)| Note {
+                start: tempo_map.seconds_at(start),
+                end: tempo_map.seconds_at(end),
+                key,
+            })
+            .collect();
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+        Self { notes }
+    }
+
+    /// Index of the note to sing at `seconds`. The highest one is taken when notes overlap, as
+    /// the melody is usually the top voice.
+    pub fn target(&self, seconds: f64) -> Option<usize> {
+        self.notes
+            .iter()
+            .enumerate()
+            .take_while(|(_, note)| note.start <= seconds)
+            .filter(|(_, note)| seconds < note.end)
+            .max_by_key(|(_, note)| note.key)
+            .map(|(i, _)| i)
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.notes.iter().map(|note| note.end).fold(0.0, f64::max)
+    }
+}
+
+fn child_text(node: Node, name: &str) -> Option<f64> {
+    let child = node.children().find(|n| n.has_tag_name(name))?;
+    child.text()?.trim().parse().ok()
+}
+
+fn note_key(note: Node) -> Option<u8> {
+    let pitch = note.children().find(|n| n.has_tag_name("pitch"))?;
+    let step = pitch.children().find(|n| n.has_tag_name("step"))?.text()?;
+    let step = match step.trim() {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return None,
+    };
+    let alter = child_text(pitch, "alter").unwrap_or(0.0).round() as i32;
+    let octave = child_text(pitch, "octave")? as i32;
+    u8::try_from((octave + 1) * 12 + step + alter).ok()
+}
+
+/// How well a note of the melody was sung.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoteScore {
+    /// Analyzed frames during the note.
+    pub frames: u32,
+    /// Frames with a detected pitch.
+    pub voiced: u32,
+    pub in_tune: u32,
+    pub cents_error_sum: f32,
+}
+
+impl NoteScore {
+    /// Share of the note's duration sung within `IN_TUNE_CENTS`.
+    pub fn in_tune_ratio(&self) -> Option<f32> {
+        (self.frames > 0).then(|| self.in_tune as f32 / self.frames as f32)
+    }
+
+    /// Mean absolute pitch error of the voiced frames, in cents.
+    pub fn mean_cents_error(&self) -> Option<f32> {
+        (self.voiced > 0).then(|| self.cents_error_sum / self.voiced as f32)
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.frames += other.frames;
+        self.voiced += other.voiced;
+        self.in_tune += other.in_tune;
+        self.cents_error_sum += other.cents_error_sum;
+    }
+}
+
+/// A run through the melody, started by the start cue and scored frame by frame.
+pub struct Practice {
+    pub melody: Arc<Melody>,
+    started_at: Instant,
+    pub scores: Vec<NoteScore>,
+}
+
+impl Practice {
+    pub fn new(melody: Arc<Melody>) -> Self {
+        let scores = vec![Default::default(); melody.notes.len()];
+        Self {
+            melody,
+            started_at: Instant::now(),
+            scores,
+        }
+    }
+
+    /// Seconds since the start cue.
+    pub fn elapsed(&self) -> f64 {
+        self.started_at.elapsed().as_secs_f64()
+    }
+
+    pub fn target(&self) -> Option<&Note> {
+        let i = self.melody.target(self.elapsed())?;
+        Some(&self.melody.notes[i])
+    }
+
+    /// Scores the frame just analyzed against the current target note.
+    pub fn record(&mut self, freq: Option<f32>) {
+        let Some(i) = self.melody.target(self.elapsed()) else {
+            return;
+        };
+        let key = self.melody.notes[i].key;
+        let score = &mut self.scores[i];
+        score.frames += 1;
+        if let Some(freq) = freq {
+            let cents = (freq_to_midi_note(&freq) - key as f32) * 100.0;
+            score.voiced += 1;
+            score.cents_error_sum += cents.abs();
+            if cents.abs() <= IN_TUNE_CENTS {
+                score.in_tune += 1;
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed() > self.melody.duration()
+    }
+
+    /// The scores of all notes so far added together.
+    pub fn total(&self) -> NoteScore {
+        let mut total = NoteScore::default();
+        self.scores.iter().for_each(|score| total.add(score));
+        total
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use midly::num::{u15, u24, u28, u4, u7};
+    use midly::{Format, Fps, Header, TrackEvent};
+
+    fn midi(timing: Timing) -> Vec<u8> {
+        let event = |delta, kind| TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        };
+        let note = |key, vel| TrackEventKind::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(vel),
+            },
+        };
+        let drum = TrackEventKind::Midi {
+            channel: u4::new(DRUM_CHANNEL),
+            message: MidiMessage::NoteOn {
+                key: u7::new(36),
+                vel: u7::new(100),
+            },
+        };
+        let track = vec![
+            event(
+                0,
+                TrackEventKind::Meta(MetaMessage::Tempo(u24::new(1_000_000))),
+            ),
+            event(0, note(69, 100)),
+            event(0, drum),
+            event(480, note(69, 0)),
+            event(480, note(72, 100)),
+            // A tempo change halfway through the note
+            event(
+                240,
+                TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))),
+            ),
+            event(240, note(72, 0)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ];
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, timing));
+        smf.tracks.push(track);
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_midi() {
+        let melody = Melody::from_midi(&midi(Timing::Metrical(u15::new(480)))).unwrap();
+        let notes: Vec<(f64, f64, u8)> = melody
+            .notes
+            .iter()
+            .map(|n| (n.start, n.end, n.key))
+            .collect();
+        assert_eq!(notes, [(0.0, 1.0, 69), (2.0, 2.75, 72)]);
+        assert!(Melody::from_midi(&midi(Timing::Metrical(u15::new(0)))).is_err());
+        assert!(Melody::from_midi(&midi(Timing::Timecode(Fps::Fps25, 0))).is_err());
+        let timecode = Melody::from_midi(&midi(Timing::Timecode(Fps::Fps25, 40))).unwrap();
+        // 1000 ticks per second, whatever the tempo says
+        assert_eq!(timecode.notes[1].start, 0.96);
+    }
+
+    #[test]
+    fn test_musicxml() {
+        let xml = r#"<score-partwise><part id="P1"><measure number="1">
+            <attributes><divisions>2</divisions></attributes>
+            <direction><sound tempo="60"/></direction>
+            <note><pitch><step>A</step><octave>4</octave></pitch><duration>2</duration></note>
+            <note><rest/><duration>1</duration></note>
+            <note><pitch><step>C</step><alter>1</alter><octave>5</octave></pitch>
+                <duration>1</duration><tie type="start"/></note>
+            <note><chord/><pitch><step>E</step><octave>4</octave></pitch><duration>1</duration></note>
+            <note><pitch><step>C</step><alter>1</alter><octave>5</octave></pitch>
+                <duration>2</duration><tie type="stop"/></note>
+        </measure></part></score-partwise>"#;
+        let melody = Melody::from_musicxml(xml).unwrap();
+        let notes: Vec<(f64, f64, u8)> = melody
+            .notes
+            .iter()
+            .map(|n| (n.start, n.end, n.key))
+            .collect();
+        assert_eq!(notes, [(0.0, 1.0, 69), (1.5, 3.0, 73), (1.5, 2.0, 64)]);
+        assert_eq!(melody.target(1.75), Some(1));
+        assert_eq!(melody.target(1.25), None);
+    }
+}
@@ -1,14 +1,240 @@
-use std::net::UdpSocket;
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant, SystemTime};
 
 use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
 
-const PARAM_FT: &str = "/avatar/parameters/FT";
-const PARAM_G_PREFIX: &str = "/avatar/parameters/G";
-const PARAM_FORMANT_PREFIX: &str = "/avatar/parameters/F";
+use crate::avatar::AvatarOptions;
+use crate::chatbox::{self, ChatboxOptions};
+use crate::mapping::{Encoding, Group, Mapping, Output};
+use crate::osc_recording::OscRecorder;
+use crate::smoothing::SmoothingOptions;
+
+const CHATBOX_INPUT: &str = "/chatbox/input";
+
+pub const DEFAULT_TARGET: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000);
+/// Suggested when the send rate is limited, about how often VRChat syncs parameters.
+pub const DEFAULT_MAX_RATE: f32 = 10.0;
+pub const MAX_RATES: RangeInclusive<f32> = 1.0..=60.0;
+/// The largest UDP payload that fits in an Ethernet frame without fragmentation.
+const DEFAULT_MAX_PAYLOAD: usize = 1472;
+/// From what holds a few messages to the largest UDP payload.
+pub const MAX_PAYLOADS: RangeInclusive<usize> = 128..=65507;
+/// "#bundle\0" and the time tag.
+const BUNDLE_HEADER_SIZE: usize = 16;
+/// When only changes are sent, every parameter is still sent this often so that an avatar
+/// loaded later catches up.
+const RESEND_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OscOptions {
+    /// Address VRChat receives OSC on.
+    pub target: SocketAddrV4,
+    /// What is sent to which parameter.
+    pub mapping: Mapping,
+    pub avatar: AvatarOptions,
+    /// Send the note of the reference melody to sing as `FT_Target`.
+    pub send_target_note: bool,
+    pub smoothing: SmoothingOptions,
+    pub chatbox: ChatboxOptions,
+    /// Frames per second sent at most. `None` sends every analyzed frame.
+    pub max_rate: Option<f32>,
+    /// Send a parameter only when it has moved by more than its threshold since it was last
+    /// sent.
+    pub changes_only: bool,
+    pub thresholds: Thresholds,
+    /// Send the messages of a frame as bundles rather than a packet each.
+    pub bundle: bool,
+    /// Largest UDP payload of a bundle, in bytes. A frame that does not fit is split over
+    /// several bundles.
+    pub max_payload: usize,
+}
+
+impl Default for OscOptions {
+    fn default() -> Self {
+        Self {
+            target: DEFAULT_TARGET,
+            mapping: Default::default(),
+            avatar: Default::default(),
+            send_target_note: false,
+            smoothing: Default::default(),
+            chatbox: Default::default(),
+            max_rate: None,
+            changes_only: false,
+            thresholds: Default::default(),
+            bundle: true,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+        }
+    }
+}
+
+impl OscOptions {
+    /// Brings values edited outside the app into the ranges the UI offers. A rate of 0 would
+    /// stop sending after the first frame, and a huge chatbox interval would panic.
+    pub fn clamp(&mut self) {
+        self.max_rate = self.max_rate.map(|rate| {
+            if rate.is_nan() {
+                DEFAULT_MAX_RATE
+            } else {
+                rate.clamp(*MAX_RATES.start(), *MAX_RATES.end())
+            }
+        });
+        self.max_payload = self
+            .max_payload
+            .clamp(*MAX_PAYLOADS.start(), *MAX_PAYLOADS.end());
+        let interval = &mut self.chatbox.interval;
+        *interval = if interval.is_nan() {
+            ChatboxOptions::default().interval
+        } else {
+            interval.clamp(*chatbox::INTERVALS.start(), *chatbox::INTERVALS.end())
+        };
+    }
+}
+
+/// How far the normalized value of a parameter has to move before it is sent again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    pub pitch: f32,
+    pub gains: f32,
+    pub formants: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            pitch: 0.001,
+            gains: 0.01,
+            formants: 0.0005,
+        }
+    }
+}
+
+impl Thresholds {
+    fn of(&self, group: Group) -> f32 {
+        match group {
+            Group::Pitch => self.pitch,
+            Group::Gains => self.gains,
+            Group::Formants => self.formants,
+            Group::Other => 0.0,
+        }
+    }
+}
+
+/// A parameter value before it is turned into messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Float(f32),
+    /// A float in 0..1 sent as two floats of 7 bits each, `_L` and `_H`.
+    Split(f32),
+    Int(i32),
+    Bool(bool),
+}
+
+impl Value {
+    fn new(value: f32, encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Float => Self::Float(value),
+            Encoding::Int => Self::Int(value.round() as i32),
+            Encoding::Bool => Self::Bool(value > 0.5),
+            Encoding::Split => Self::Split(value),
+        }
+    }
+
+    fn as_f32(&self) -> f32 {
+        match *self {
+            Self::Float(v) | Self::Split(v) => v,
+            Self::Int(v) => v as f32,
+            Self::Bool(v) => f32::from(u8::from(v)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Param {
+    addr: String,
+    value: Value,
+    /// Changes up to this are not sent when only changes are sent.
+    threshold: f32,
+}
+
+impl Param {
+    fn new(addr: &str, value: Value, threshold: f32) -> Self {
+        Self {
+            addr: addr.to_owned(),
+            value,
+            threshold,
+        }
+    }
+
+    fn to_messages(&self) -> Vec<OscMessage> {
+        match self.value {
+            Value::Float(v) => vec![new_float_message(&self.addr, v)],
+            Value::Int(v) => vec![new_int_message(&self.addr, v)],
+            Value::Bool(v) => {
+                let mut message = OscMessage::from(self.addr.as_str());
+                message.args.push(OscType::from(v));
+                vec![message]
+            }
+            Value::Split(v) => {
+                let (l, h) = new_split_float_message(&self.addr, v);
+                vec![l, h]
+            }
+        }
+    }
+}
+
+/// Decides which frames and parameters are sent.
+#[derive(Default)]
+struct SendFilter {
+    sent_at: Option<Instant>,
+    resent_at: Option<Instant>,
+    last_sent: HashMap<String, f32>,
+}
+
+impl SendFilter {
+    fn filter(&mut self, params: Vec<Param>, options: &OscOptions, now: Instant) -> Vec<Param> {
+        if let (Some(max_rate), Some(sent_at)) = (options.max_rate, self.sent_at) {
+            if now.duration_since(sent_at).as_secs_f32() < 1.0 / max_rate {
+                return vec![];
+            }
+        }
+        self.sent_at = Some(now);
+        let resend = !options.changes_only
+            || self
+                .resent_at
+                .is_none_or(|at| now.duration_since(at) >= RESEND_INTERVAL);
+        if resend {
+            self.resent_at = Some(now);
+        }
+        params
+            .into_iter()
+            .filter(|param| {
+                let value = param.value.as_f32();
+                let changed = self
+                    .last_sent
+                    .get(&param.addr)
+                    .is_none_or(|last| (value - last).abs() > param.threshold);
+                let send = resend || changed;
+                if send {
+                    self.last_sent.insert(param.addr.clone(), value);
+                }
+                send
+            })
+            .collect()
+    }
+}
 
 pub struct OscSender {
     sock: UdpSocket,
+    /// Whether `sock` is connected to the target of `options`. Nothing is sent until it is.
+    connected: bool,
+    options: OscOptions,
+    filter: SendFilter,
 }
 
 fn new_float_message(addr: &str, v: f32) -> OscMessage {
@@ -17,6 +243,12 @@ fn new_float_message(addr: &str, v: f32) -> OscMessage {
     message
 }
 
+fn new_int_message(addr: &str, v: i32) -> OscMessage {
+    let mut message = OscMessage::from(addr);
+    message.args.push(OscType::from(v));
+    message
+}
+
 fn split_float(v: f32) -> (f32, f32) {
     let i = (v * 0x3FFF as f32) as u32;
     let l = (i & 0x7F) as f32 / 127.0;
@@ -33,41 +265,207 @@ fn new_split_float_message(addr_base: &str, v: f32) -> (OscMessage, OscMessage)
     (l, h)
 }
 
+/// Groups `messages` into bundles whose encoded size is at most `max_payload` bytes. A message
+/// too large on its own gets a bundle to itself.
+fn pack_bundles(messages: Vec<OscMessage>, max_payload: usize) -> Vec<Vec<OscMessage>> {
+    let mut bundles: Vec<Vec<OscMessage>> = vec![];
+    let mut size = BUNDLE_HEADER_SIZE;
+    for message in messages {
+        // Each element of a bundle is preceded by its size
+        let message_size = 4 + encoder::encode(&OscPacket::Message(message.clone()))
+            .map_or(0, |bytes| bytes.len());
+        match bundles.last_mut() {
+            Some(bundle) if size + message_size <= max_payload => {
+                bundle.push(message);
+                size += message_size;
+            }
+            _ => {
+                bundles.push(vec![message]);
+                size = BUNDLE_HEADER_SIZE + message_size;
+            }
+        }
+    }
+    bundles
+}
+
 impl OscSender {
-    pub fn new() -> Self {
-        let host_addr = "127.0.0.1:0";
-        let to_addr = "127.0.0.1:9000";
-        let sock = UdpSocket::bind(host_addr).unwrap();
-        sock.connect(to_addr).unwrap();
-        Self { sock }
-    }
-    fn send_bundle(&self, vs: Vec<OscMessage>) {
+    /// Fails only if no socket can be opened. A target that cannot be connected to is reported
+    /// by `configure`.
+    pub fn new(options: &OscOptions) -> io::Result<Self> {
+        let host_addr = "0.0.0.0:0";
+        let sock = UdpSocket::bind(host_addr)?;
+        let connected = sock.connect(options.target).is_ok();
+        Ok(Self {
+            sock,
+            connected,
+            options: options.clone(),
+            filter: Default::default(),
+        })
+    }
+    /// Applies changed options, reconnecting if the target has changed. Fails while the target
+    /// cannot be connected to, such as port 0.
+    pub fn configure(&mut self, options: &OscOptions) -> io::Result<()> {
+        let target_changed = options.target != self.options.target;
+        self.options.clone_from(options);
+        if target_changed || !self.connected {
+            // Connecting a UDP socket sends nothing, so retrying every frame is cheap
+            self.connected = false;
+            self.sock.connect(options.target)?;
+            self.connected = true;
+        }
+        Ok(())
+    }
+    fn send_packet(&self, packet: &OscPacket) -> io::Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+        let bytes = encoder::encode(packet).map_err(|e| io::Error::other(format!("{e:?}")))?;
+        match self.sock.send(&bytes) {
+            // Reported for an earlier packet while nothing listens on the target, such as
+            // before VRChat is started
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+    fn send_bundle(
+        &self,
+        vs: Vec<OscMessage>,
+        recorder: &mut Option<&mut OscRecorder>,
+    ) -> io::Result<()> {
+        if let Some(recorder) = recorder {
+            recorder.record(&vs, true);
+        }
+        let timetag = SystemTime::now()
+            .try_into()
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
         let bundle = OscBundle {
-            timetag: SystemTime::now().try_into().unwrap(),
+            timetag,
             content: vs.into_iter().map(OscPacket::Message).collect(),
         };
-        let packet = OscPacket::Bundle(bundle);
-        let bytes = encoder::encode(&packet).unwrap();
-        self.sock.send(&bytes).unwrap();
+        self.send_packet(&OscPacket::Bundle(bundle))
+    }
+    fn send_message(
+        &self,
+        v: OscMessage,
+        recorder: &mut Option<&mut OscRecorder>,
+    ) -> io::Result<()> {
+        if let Some(recorder) = recorder {
+            recorder.record(std::slice::from_ref(&v), false);
+        }
+        self.send_packet(&OscPacket::Message(v))
     }
-    pub fn send_param(&self, freq: f32, gains: Vec<f32>, formants: Vec<f32>) {
-        let mut vs: Vec<OscMessage> = gains
+    /// Shows `text` in the chatbox at once, without the notification sound.
+    pub fn send_chatbox(
+        &self,
+        text: &str,
+        mut recorder: Option<&mut OscRecorder>,
+    ) -> io::Result<()> {
+        let mut message = OscMessage::from(CHATBOX_INPUT);
+        message.args.push(OscType::from(text));
+        message.args.push(OscType::from(true));
+        message.args.push(OscType::from(false));
+        self.send_message(message, &mut recorder)
+    }
+    /// Sends the outputs of the mapping for a frame, as far as the rate limit and the
+    /// thresholds let them through. What is sent is also written to `recorder` when given.
+    pub fn send(
+        &mut self,
+        outputs: Vec<Output>,
+        mut recorder: Option<&mut OscRecorder>,
+    ) -> io::Result<()> {
+        let thresholds = self.options.thresholds;
+        let params = outputs
             .into_iter()
-            .enumerate()
-            .map(|(i, g)| {
-                let addr = format!("{}{}", PARAM_G_PREFIX, i + 1);
-                new_float_message(&addr, g)
+            .map(|output| {
+                let value = Value::new(output.value, output.encoding);
+                Param::new(&output.address, value, thresholds.of(output.group))
             })
             .collect();
-        let (ft_l, ft_h) = new_split_float_message(PARAM_FT, freq);
-        vs.push(ft_l);
-        vs.push(ft_h);
-        formants.into_iter().enumerate().for_each(|(i, formant)| {
-            let addr = format!("{}{}", PARAM_FORMANT_PREFIX, i + 1);
-            let (l, h) = new_split_float_message(&addr, formant);
-            vs.push(l);
-            vs.push(h)
-        });
-        self.send_bundle(vs);
+        let params = self.filter.filter(params, &self.options, Instant::now());
+        let vs: Vec<OscMessage> = params.iter().flat_map(Param::to_messages).collect();
+        if !self.options.bundle {
+            return vs
+                .into_iter()
+                .try_for_each(|v| self.send_message(v, &mut recorder));
+        }
+        pack_bundles(vs, self.options.max_payload)
+            .into_iter()
+            .try_for_each(|bundle| self.send_bundle(bundle, &mut recorder))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pack_bundles() {
+        let messages: Vec<OscMessage> = (1..=40)
+            .map(|i| new_float_message(&format!("/avatar/parameters/G{i}"), 0.5))
+            .collect();
+        let bundles = pack_bundles(messages.clone(), 512);
+        assert!(bundles.len() > 1);
+        assert_eq!(bundles.concat(), messages);
+        for content in bundles {
+            let bundle = OscBundle {
+                timetag: SystemTime::now().try_into().unwrap(),
+                content: content.into_iter().map(OscPacket::Message).collect(),
+            };
+            assert!(encoder::encode(&OscPacket::Bundle(bundle)).unwrap().len() <= 512);
+        }
+    }
+
+    #[test]
+    fn test_unreachable_target() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let std::net::SocketAddr::V4(target) = receiver.local_addr().unwrap() else {
+            unreachable!()
+        };
+        // Refused as the socket is not allowed to broadcast
+        let options = OscOptions {
+            target: SocketAddrV4::new(Ipv4Addr::BROADCAST, 9000),
+            ..Default::default()
+        };
+        let mut sender = OscSender::new(&options).unwrap();
+        let sent = sender
+            .configure(&options)
+            .and_then(|_| sender.send_chatbox("a", None));
+        assert!(sent.is_err());
+        sender.configure(&OscOptions { target, ..options }).unwrap();
+        sender.send_chatbox("b", None).unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        let (_, packet) = rosc::decoder::decode_udp(&buf[..len]).unwrap();
+        let OscPacket::Message(message) = packet else {
+            panic!("not a message")
+        };
+        assert_eq!(message.args[0], OscType::from("b"));
+    }
+
+    #[test]
+    fn test_send_filter() {
+        let options = OscOptions {
+            max_rate: Some(10.0),
+            changes_only: true,
+            ..Default::default()
+        };
+        let params = |value| {
+            vec![Param::new(
+                "/avatar/parameters/FT",
+                Value::Split(value),
+                0.01,
+            )]
+        };
+        let mut filter = SendFilter::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(filter.filter(params(0.5), &options, at(0)).len(), 1);
+        // Within the rate limit
+        assert!(filter.filter(params(0.9), &options, at(50)).is_empty());
+        // Below the threshold
+        assert!(filter.filter(params(0.505), &options, at(100)).is_empty());
+        assert_eq!(filter.filter(params(0.52), &options, at(200)).len(), 1);
+        // Sent again after a while even if unchanged
+        assert_eq!(filter.filter(params(0.52), &options, at(1000)).len(), 1);
     }
 }
@@ -0,0 +1,250 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddrV4, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// An argument of a recorded message. Only the types the sender uses are kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    String(String),
+}
+
+/// A packet as it was sent, stored as a line of JSON so that recordings can also be written
+/// by hand.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedPacket {
+    /// Seconds since the recording started.
+    pub time: f64,
+    /// Whether the messages were sent as one bundle rather than a packet each.
+    pub bundle: bool,
+    /// (address, arguments) of each message.
+    pub messages: Vec<(String, Vec<Arg>)>,
+}
+
+impl RecordedPacket {
+    fn new(time: f64, messages: &[OscMessage], bundle: bool) -> Self {
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let args = message
+                    .args
+                    .iter()
+                    .filter_map(|arg| match arg {
+                        OscType::Int(i) => Some(Arg::Int(*i)),
+                        OscType::Float(f) => Some(Arg::Float(*f)),
+                        OscType::Bool(b) => Some(Arg::Bool(*b)),
+                        OscType::String(s) => Some(Arg::String(s.clone())),
+                        _ => None,
+                    })
+                    .collect();
+                (message.addr.clone(), args)
+            })
+            .collect();
+        Self {
+            time,
+            bundle,
+            messages,
+        }
+    }
+
+    fn to_packets(&self) -> Vec<OscPacket> {
+        let messages = self.messages.iter().map(|(addr, args)| {
+            let args = args
+                .iter()
+                .map(|arg| match arg {
+                    Arg::Int(i) => OscType::Int(*i),
+                    Arg::Float(f) => OscType::Float(*f),
+                    Arg::Bool(b) => OscType::Bool(*b),
+                    Arg::String(s) => OscType::String(s.clone()),
+                })
+                .collect();
+            OscPacket::Message(OscMessage {
+                addr: addr.clone(),
+                args,
+            })
+        });
+        if !self.bundle {
+            return messages.collect();
+        }
+        let bundle = OscBundle {
+            timetag: SystemTime::now().try_into().unwrap(),
+            content: messages.collect(),
+        };
+        vec![OscPacket::Bundle(bundle)]
+    }
+}
+
+/// Writes every packet the sender emits, with the time it was sent, while recording.
+pub struct OscRecorder {
+    file: BufWriter<File>,
+    started_at: Instant,
+    pub packets: usize,
+    /// The first write that failed. Nothing more is written after it.
+    pub error: Option<String>,
+}
+
+impl OscRecorder {
+    pub fn create(path: &Path) -> Res<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            started_at: Instant::now(),
+            packets: 0,
+            error: None,
+        })
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.started_at.elapsed().as_secs_f64()
+    }
+
+    pub fn record(&mut self, messages: &[OscMessage], bundle: bool) {
+        if self.error.is_some() {
+            return;
+        }
+        let packet = RecordedPacket::new(self.seconds(), messages, bundle);
+        let result = serde_json::to_writer(&mut self.file, &packet)
+            .map_err(|e| e.to_string())
+            .and_then(|()| self.file.write_all(b"\n").map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => self.packets += 1,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    pub fn finish(mut self) -> Res<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+pub fn load(path: &Path) -> Res<Vec<RecordedPacket>> {
+    let mut packets: Vec<RecordedPacket> = vec![];
+    for line in std::fs::read_to_string(path)?.lines() {
+        if !line.trim().is_empty() {
+            packets.push(serde_json::from_str(line)?);
+        }
+    }
+    packets.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(packets)
+}
+
+/// Sends a recording to a target on a background thread, keeping the original timing, until
+/// it ends or the replayer is dropped.
+pub struct OscReplayer {
+    pub duration: f64,
+    started_at: Instant,
+    finished: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+}
+
+impl OscReplayer {
+    pub fn start(path: &Path, target: SocketAddrV4) -> Res<Self> {
+        let packets = load(path)?;
+        let sock = UdpSocket::bind("0.0.0.0:0")?;
+        sock.connect(target)?;
+        let duration = packets.last().map_or(0.0, |packet| packet.time);
+        let started_at = Instant::now();
+        let finished = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_finished, thread_stop) = (finished.clone(), stop.clone());
+        thread::spawn(move || {
+            for packet in packets {
+                let at = started_at + Duration::from_secs_f64(packet.time.max(0.0));
+                thread::sleep(at.saturating_duration_since(Instant::now()));
+                if thread_stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                for packet in packet.to_packets() {
+                    // A target that is not listening is not an error for UDP
+                    if let Ok(bytes) = encoder::encode(&packet) {
+                        let _ = sock.send(&bytes);
+                    }
+                }
+            }
+            thread_finished.store(true, Ordering::Relaxed);
+        });
+        Ok(Self {
+            duration,
+            started_at,
+            finished,
+            stop,
+        })
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.started_at.elapsed().as_secs_f64().min(self.duration)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OscReplayer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = crate::utils::temp_path("osc.jsonl");
+        let mut recorder = OscRecorder::create(&path).unwrap();
+        let messages = [
+            OscMessage {
+                addr: "/avatar/parameters/FT_L".into(),
+                args: vec![OscType::Float(0.1)],
+            },
+            OscMessage {
+                addr: "/avatar/parameters/FT_Min".into(),
+                args: vec![OscType::Int(40)],
+            },
+        ];
+        recorder.record(&messages, true);
+        recorder.record(&messages[..1], false);
+        recorder.finish().unwrap();
+        let packets = load(&path).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].messages[0].1, [Arg::Float(0.1)]);
+        assert_eq!(packets[0].messages[1].1, [Arg::Int(40)]);
+
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let std::net::SocketAddr::V4(target) = receiver.local_addr().unwrap() else {
+            unreachable!();
+        };
+        let _replayer = OscReplayer::start(&path, target).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut buf = [0; 1024];
+        let mut received = vec![];
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buf).unwrap();
+            received.push(rosc::decoder::decode_udp(&buf[..len]).unwrap().1);
+        }
+        let OscPacket::Bundle(bundle) = &received[0] else {
+            panic!("expected a bundle");
+        };
+        assert_eq!(bundle.content, messages.clone().map(OscPacket::Message));
+        assert_eq!(received[1], OscPacket::Message(messages[0].clone()));
+    }
+}
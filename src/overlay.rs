@@ -0,0 +1,368 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::analyzer::freq_to_midi_note;
+use crate::utils;
+use crate::vowel::VowelSet;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+pub const DEFAULT_PORT: u16 = 9010;
+/// How often the server checks whether it should stop while no one connects.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(200);
+/// A connection that does not finish its request within this long is closed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// A client that does not take a frame within this long is dropped, rather than holding up the
+/// analysis.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// The page for the OBS browser source, connecting back to the port it is served from.
+const OVERLAY_HTML: &str = include_str!("overlay.html");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverlayOptions {
+    /// Serve the overlay page and stream frames to it.
+    pub enabled: bool,
+    /// Port on localhost the page and the WebSocket are served on.
+    pub port: u16,
+    /// The vowels `vowel` is chosen from.
+    pub vowels: VowelSet,
+}
+
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_PORT,
+            vowels: Default::default(),
+        }
+    }
+}
+
+/// The features of a frame, sent to the clients as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Frame {
+    /// Pitch in Hz. `None` while no pitch is detected.
+    pub f0: Option<f32>,
+    /// MIDI note number of the pitch, with the deviation from the note as the fraction.
+    pub note: Option<f32>,
+    pub rms: f32,
+    /// Normalized gains of the harmonics, from the fundamental.
+    pub gains: Vec<f32>,
+    /// Formants in Hz, from F1.
+    pub formants: Vec<f64>,
+    /// The closest Japanese vowel.
+    pub vowel: Option<&'static str>,
+    pub metrics: Metrics,
+}
+
+/// Values derived from the features, so that the page does not have to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Metrics {
+    /// Nearest note name, such as `A3`.
+    pub note_name: Option<String>,
+    /// Deviation from the nearest note.
+    pub cents: Option<f32>,
+    /// Input level in dBFS.
+    pub volume_db: f32,
+    /// The note of the reference melody to sing now, while practicing.
+    pub target_note: Option<u8>,
+}
+
+impl Frame {
+    pub fn new(
+        freq: Option<f32>,
+        rms: f32,
+        gains: &[f32],
+        formants: &[f64],
+        vowels: VowelSet,
+        target_note: Option<u8>,
+    ) -> Self {
+        let note = freq.map(|f| freq_to_midi_note(&f));
+        // Formants are only meaningful while the voice has a pitch
+        let vowel = match formants {
+            [f1, f2, ..] if freq.is_some() => vowels.classify(*f1, *f2).map(|v| v.symbol),
+            _ => None,
+        };
+        Self {
+            f0: freq,
+            note,
+            rms,
+            gains: gains.to_vec(),
+            formants: formants.to_vec(),
+            vowel,
+            metrics: Metrics {
+                note_name: note.map(|n| utils::midi_note_number_to_str(n.round() as f64)),
+                cents: note.map(|n| (n - n.round()) * 100.0),
+                volume_db: utils::to_db(rms),
+                target_note,
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Request {
+    Page,
+    WebSocket { key: String },
+    NotFound,
+}
+
+impl Request {
+    /// Reads the request line and the headers, without the trailing empty line.
+    fn parse(lines: &[String]) -> Self {
+        let path = lines
+            .first()
+            .and_then(|line| line.split(' ').nth(1))
+            .unwrap_or_default();
+        let header = |name: &str| {
+            lines.iter().skip(1).find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.trim()
+                    .eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_owned())
+            })
+        };
+        let upgrade = header("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+        match header("Sec-WebSocket-Key") {
+            Some(key) if upgrade => Self::WebSocket { key },
+            _ => match path.split('?').next() {
+                Some("/" | "/index.html") => Self::Page,
+                _ => Self::NotFound,
+            },
+        }
+    }
+}
+
+/// Answers a connection, returning it as a WebSocket if it asked for one.
+fn accept(stream: TcpStream) -> std::io::Result<Option<WebSocket<TcpStream>>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut lines = vec![];
+    let mut reader = BufReader::new(&stream);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        lines.push(line.trim_end().to_owned());
+    }
+    let response = match Request::parse(&lines) {
+        Request::WebSocket { key } => {
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                derive_accept_key(key.as_bytes())
+            );
+            (&stream).write_all(response.as_bytes())?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            return Ok(Some(WebSocket::from_raw_socket(stream, Role::Server, None)));
+        }
+        Request::Page => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{OVERLAY_HTML}",
+            OVERLAY_HTML.len()
+        ),
+        Request::NotFound => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+        }
+    };
+    (&stream).write_all(response.as_bytes())?;
+    Ok(None)
+}
+
+/// Serves the overlay page on a background thread and streams frames to the WebSocket clients
+/// connected to it, until dropped.
+pub struct OverlayServer {
+    port: u16,
+    clients: Arc<Mutex<Vec<WebSocket<TcpStream>>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OverlayServer {
+    /// Listens on `port` of localhost, or on any free port if it is 0.
+    pub fn start(port: u16) -> Res<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let clients = Arc::new(Mutex::new(vec![]));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_clients, thread_stop) = (clients.clone(), stop.clone());
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                let Ok((stream, _)) = listener.accept() else {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                };
+                // A client slow to send its request does not keep the others waiting
+                let clients = thread_clients.clone();
+                thread::spawn(move || {
+                    if let Ok(Some(websocket)) = accept(stream) {
+                        clients.lock().unwrap().push(websocket);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            port,
+            clients,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Number of WebSocket clients connected.
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Sends `frame` to every client, dropping the ones that have gone away or fallen behind.
+    pub fn broadcast(&self, frame: &Frame) {
+        let Ok(json) = serde_json::to_string(frame) else {
+            return;
+        };
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|websocket| websocket.send(Message::text(json.clone())).is_ok());
+    }
+}
+
+impl Drop for OverlayServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Frees the port before a server on the same port is started
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What the analyzer knows about the overlay server.
+#[derive(Debug, Default, Clone)]
+pub struct OverlayStatus {
+    /// The port the server listens on, while it runs.
+    pub port: Option<u16>,
+    /// Number of WebSocket clients connected.
+    pub clients: usize,
+    /// Why the server could not start.
+    pub error: Option<String>,
+}
+
+/// Keeps a server running while the overlay is enabled.
+#[derive(Default)]
+pub struct Overlay {
+    /// The port and the server started on it. A failed start is not retried until the options
+    /// change.
+    server: Option<(u16, Result<OverlayServer, String>)>,
+}
+
+impl Overlay {
+    pub fn update(&mut self, options: &OverlayOptions) -> OverlayStatus {
+        if !options.enabled {
+            self.server = None;
+            return Default::default();
+        }
+        if self
+            .server
+            .as_ref()
+            .is_none_or(|(port, _)| *port != options.port)
+        {
+            self.server = None;
+            let server = OverlayServer::start(options.port);
+            self.server = Some((options.port, server.map_err(|e| e.to_string())));
+        }
+        match &self.server {
+            Some((_, Ok(server))) => OverlayStatus {
+                port: Some(server.port()),
+                clients: server.clients(),
+                error: None,
+            },
+            Some((_, Err(e))) => OverlayStatus {
+                error: Some(e.clone()),
+                ..Default::default()
+            },
+            None => Default::default(),
+        }
+    }
+
+    /// The running server, if the overlay is enabled and the server has started.
+    pub fn server(&self) -> Option<&OverlayServer> {
+        match &self.server {
+            Some((_, Ok(server))) => Some(server),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::time::Instant;
+
+    #[test]
+    fn test_frame() {
+        let frame = Frame::new(
+            Some(220.0),
+            0.1,
+            &[1.0, 0.5],
+            &[750.0, 1200.0],
+            VowelSet::Male,
+            Some(57),
+        );
+        assert_eq!(frame.note, Some(57.0));
+        assert_eq!(frame.vowel, Some("あ"));
+        assert_eq!(frame.metrics.note_name.as_deref(), Some("A3"));
+        assert_eq!(frame.metrics.volume_db, -20.0);
+        let silent = Frame::new(None, 0.0, &[0.0], &[750.0, 1200.0], VowelSet::Male, None);
+        assert_eq!(silent.vowel, None);
+        let json = serde_json::to_value(&silent).unwrap();
+        assert_eq!(json["f0"], serde_json::Value::Null);
+        assert_eq!(json["gains"], serde_json::json!([0.0]));
+    }
+
+    #[test]
+    fn test_server() {
+        let server = OverlayServer::start(0).unwrap();
+        let address = (Ipv4Addr::LOCALHOST, server.port());
+
+        let mut page = TcpStream::connect(address).unwrap();
+        page.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        page.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(OVERLAY_HTML));
+
+        let stream = TcpStream::connect(address).unwrap();
+        let url = format!("ws://localhost:{}/", server.port());
+        let (mut client, _) = tungstenite::client(url, stream).unwrap();
+        // The server adds the client once the handshake is done on its thread
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        while server.clients() == 0 {
+            assert!(Instant::now() < deadline, "the client was not added");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let frame = Frame::new(Some(440.0), 0.5, &[0.8], &[], VowelSet::Male, None);
+        server.broadcast(&frame);
+        let message = client.read().unwrap();
+        let json: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(json["f0"], 440.0);
+        assert_eq!(json["metrics"]["note_name"], "A4");
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{AnalyzerOptions, PitchAlgorithm, PitchRange, CHUNK_SIZE};
+use crate::mapping::Mapping;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// A named set of analyzer options, and optionally the OSC mapping, that can be switched to at
+/// once.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub options: AnalyzerOptions,
+    /// Replaces the OSC mapping when the profile is applied. `None` keeps the current one.
+    #[serde(default)]
+    pub mapping: Option<Mapping>,
+}
+
+impl Profile {
+    pub fn new(name: &str, options: AnalyzerOptions) -> Self {
+        Self {
+            name: name.to_owned(),
+            options,
+            mapping: None,
+        }
+    }
+
+    /// Profiles that are always available and cannot be overwritten.
+    pub fn builtin() -> Vec<Self> {
+        let default = AnalyzerOptions::default();
+        vec![
+            Self::new(
+                "Male speech",
+                AnalyzerOptions {
+                    pitch_range: PitchRange::new(36, 67),
+                    gate_enabled: true,
+                    ..default
+                },
+            ),
+            Self::new(
+                "Soprano singing",
+                AnalyzerOptions {
+                    pitch_range: PitchRange::new(55, 96),
+                    pitch_algorithm: PitchAlgorithm::Yin,
+                    fft_size: CHUNK_SIZE * 2,
+                    lpc_order: 16,
+                    gate_enabled: true,
+                    ..default
+                },
+            ),
+            Self::new(
+                "Streaming low-CPU",
+                AnalyzerOptions {
+                    fft_size: CHUNK_SIZE * 2,
+                    lpc_order: 12,
+                    harmonics: 10,
+                    gate_enabled: true,
+                    gate_db: -45.0,
+                    spectrogram_seconds: 3.0,
+                    ..default
+                },
+            ),
+        ]
+    }
+
+    pub fn import(path: &Path) -> Res<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    fn from_json(json: &str) -> Res<Self> {
+        let profile: Self = serde_json::from_str(json)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Checks that the analyzer can run with the options and that the mapping can be sent.
+    pub fn validate(&self) -> Res<()> {
+        self.options.validate()?;
+        if let Some(mapping) = &self.mapping {
+            mapping.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn export(&self, path: &Path) -> Res<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rejected_profiles() {
+        for profile in Profile::builtin() {
+            let json = serde_json::to_string(&profile).unwrap();
+            assert_eq!(Profile::from_json(&json).unwrap(), profile);
+        }
+        let mut with_mapping = Profile::new("Mapped", Default::default());
+        with_mapping.mapping = Some(Mapping {
+            rules: Mapping::default().rules[..2].to_vec(),
+        });
+        let path = crate::utils::temp_path("profile.json");
+        with_mapping.export(&path).unwrap();
+        let imported = Profile::import(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported.unwrap(), with_mapping);
+        let mut bad_mapping = with_mapping;
+        bad_mapping.mapping.as_mut().unwrap().rules[0].address = "no-slash".into();
+        let json = serde_json::to_string(&bad_mapping).unwrap();
+        assert!(Profile::from_json(&json).is_err());
+        let profile = |options: &str| format!(r#"{{ "name": "Bad", "options": {options} }}"#);
+        for options in [
+            r#"{ "fft_size": 256 }"#,
+            r#"{ "fft_size": 0 }"#,
+            r#"{ "lpc_order": 0 }"#,
+            r#"{ "lpc_order": 64 }"#,
+            r#"{ "harmonics": 0 }"#,
+            r#"{ "harmonics": 100 }"#,
+            r#"{ "pitch_range": { "low": 60, "high": 60 } }"#,
+            r#"{ "pitch_range": { "low": 0, "high": 0 } }"#,
+        ] {
+            assert!(Profile::from_json(&profile(options)).is_err(), "{options}");
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{Analyzer, AnalyzerOptions, CHUNK_SIZE};
+use crate::osc::OscOptions;
+use crate::overlay::OverlayOptions;
+use crate::sound_device::{read_wav, Capturer, Playback, Sound};
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Chunks analyzed ahead of the position sought to, so that the pitch history leading up to it
+/// is shown.
+const HISTORY_CHUNKS: usize = 200;
+
+/// A line of the feature sidecar, stored as JSON Lines next to the WAV file of the session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    /// The first line of every session.
+    Start {
+        session: String,
+        samplerate: usize,
+        /// Seconds since the Unix epoch.
+        started_at: f64,
+    },
+    /// The options from this point on, written at the start and whenever they change.
+    Options(AnalyzerOptions),
+    Frame(Frame),
+}
+
+/// What the analyzer found in a frame.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Seconds from the start of the WAV file to the end of the frame.
+    pub time: f64,
+    pub rms: f32,
+    pub freq: Option<f32>,
+    pub gains: Vec<f32>,
+    pub formants: Vec<f64>,
+}
+
+/// Writes the captured mono stream and the features of every frame while recording.
+pub struct SessionRecorder {
+    pub id: String,
+    paths: SessionPaths,
+    started_at: f64,
+    /// Created with the first chunk, as the sample rate is not known before.
+    wav: Option<hound::WavWriter<BufWriter<File>>>,
+    features: BufWriter<File>,
+    samplerate: usize,
+    samples: u64,
+    options: Option<AnalyzerOptions>,
+}
+
+impl SessionRecorder {
+    /// Starts a session in `dir`, named after the current time. A session started within the
+    /// same second as another gets a suffix, such as `-2`, instead of overwriting it.
+    pub fn new(dir: &Path) -> Res<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let base = session_id(now.as_secs());
+        std::fs::create_dir_all(dir)?;
+        let mut n = 1;
+        let (id, paths, file) = loop {
+            let id = match n {
+                1 => base.clone(),
+                _ => format!("{base}-{n}"),
+            };
+            let paths = SessionPaths::new(dir, &id);
+            // The WAV is only created with the first chunk, so it is checked here as well
+            if !paths.wav.exists() {
+                match File::create_new(&paths.features) {
+                    Ok(file) => break (id, paths, file),
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            n += 1;
+        };
+        let features = BufWriter::new(file);
+        Ok(Self {
+            id,
+            paths,
+            started_at: now.as_secs_f64(),
+            wav: None,
+            features,
+            samplerate: 0,
+            samples: 0,
+            options: None,
+        })
+    }
+
+    pub fn seconds(&self) -> f64 {
+        if self.samplerate == 0 {
+            return 0.0;
+        }
+        self.samples as f64 / self.samplerate as f64
+    }
+
+    /// Appends a chunk of the input before the gain is applied, and the features the analyzer
+    /// found once the chunk was added. The time of `frame` is set from the samples written.
+    pub fn push(&mut self, input: &Sound, options: &AnalyzerOptions, frame: Frame) -> Res<()> {
+        let wav = match &mut self.wav {
+            Some(_) if input.samplerate != self.samplerate => {
+                return Err("the sample rate of the input has changed".into());
+            }
+            Some(wav) => wav,
+            None => {
+                let spec = hound::WavSpec {
+                    channels: 1,
+                    sample_rate: input.samplerate as u32,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                };
+                self.samplerate = input.samplerate;
+                let start = Record::Start {
+                    session: self.id.clone(),
+                    samplerate: input.samplerate,
+                    started_at: self.started_at,
+                };
+                write_record(&mut self.features, &start)?;
+                let wav = BufWriter::new(File::create_new(&self.paths.wav)?);
+                self.wav.insert(hound::WavWriter::new(wav, spec)?)
+            }
+        };
+        for &sample in &input.samples {
+            wav.write_sample(sample)?;
+        }
+        self.samples += input.samples.len() as u64;
+        if self.options.as_ref() != Some(options) {
+            self.options = Some(*options);
+            write_record(&mut self.features, &Record::Options(*options))?;
+        }
+        let frame = Frame {
+            time: self.seconds(),
+            ..frame
+        };
+        write_record(&mut self.features, &Record::Frame(frame))
+    }
+
+    /// Completes the WAV header and flushes both files.
+    pub fn finish(mut self) -> Res<SessionPaths> {
+        self.features.flush()?;
+        match self.wav {
+            Some(wav) => wav.finalize()?,
+            None => return Err("nothing was recorded".into()),
+        }
+        Ok(self.paths)
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> Res<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// The two files of a session.
+pub struct SessionPaths {
+    pub wav: PathBuf,
+    pub features: PathBuf,
+}
+
+impl SessionPaths {
+    pub fn new(dir: &Path, id: &str) -> Self {
+        Self {
+            wav: dir.join(format!("{id}.wav")),
+            features: dir.join(format!("{id}.jsonl")),
+        }
+    }
+}
+
+/// A recorded session loaded for replay.
+pub struct Session {
+    pub id: String,
+    pub samplerate: usize,
+    pub samples: Vec<f32>,
+    /// The frame recorded for each chunk of `samples`.
+    pub frames: Vec<Frame>,
+    /// (first frame, options) of each change of the options.
+    options: Vec<(usize, AnalyzerOptions)>,
+}
+
+impl Session {
+    /// Loads the feature sidecar at `path` and the WAV file next to it.
+    pub fn load(path: &Path) -> Res<Self> {
+        let mut start = None;
+        let mut frames = vec![];
+        let mut options = vec![];
+        for line in std::fs::read_to_string(path)?.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line)? {
+                Record::Start {
+                    session,
+                    samplerate,
+                    ..
+                } => start = Some((session, samplerate)),
+                Record::Options(o) => {
+                    o.validate()?;
+                    options.push((frames.len(), o));
+                }
+                Record::Frame(frame) => frames.push(frame),
+            }
+        }
+        let (id, samplerate) = start.ok_or("not a session file")?;
+        let (mut channels, wav_samplerate) = read_wav(&path.with_extension("wav"))?;
+        if wav_samplerate != samplerate {
+            return Err("the WAV file does not belong to the session".into());
+        }
+        Ok(Self {
+            id,
+            samplerate,
+            samples: channels.swap_remove(0),
+            frames,
+            options,
+        })
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.samples.len() / CHUNK_SIZE
+    }
+
+    pub fn seconds_at(&self, chunk: usize) -> f64 {
+        ((chunk + 1) * CHUNK_SIZE) as f64 / self.samplerate as f64
+    }
+
+    /// The options that were in effect when `chunk` was analyzed.
+    pub fn options_at(&self, chunk: usize) -> AnalyzerOptions {
+        self.options
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= chunk)
+            .or(self.options.first())
+            .map_or_else(Default::default, |(_, options)| *options)
+    }
+}
+
+/// Plays a session back through an analyzer of its own, so that it is shown in the same
+/// graphs as the live input and can be sent over OSC again.
+pub struct Replay {
+    pub session: Arc<Session>,
+    pub playback: Arc<Mutex<Playback>>,
+    pub analyzer: Analyzer,
+}
+
+impl Replay {
+    pub fn new(
+        session: Arc<Session>,
+        osc_options: Option<OscOptions>,
+        overlay_options: Option<OverlayOptions>,
+    ) -> Self {
+        let playback = Arc::new(Mutex::new(Playback::default()));
+        let capturer = Capturer::from_playback(
+            session.samples.clone(),
+            session.samplerate,
+            CHUNK_SIZE,
+            playback.clone(),
+        );
+        let analyzer = Analyzer::new(
+            capturer,
+            session.options_at(0),
+            osc_options,
+            overlay_options,
+        );
+        Self {
+            session,
+            playback,
+            analyzer,
+        }
+    }
+
+    /// The chunk analyzed last.
+    pub fn current(&self) -> usize {
+        self.playback.lock().unwrap().position.saturating_sub(1)
+    }
+
+    /// Moves to `chunk`, analyzing the chunks before it at once to fill the history.
+    pub fn seek(&self, chunk: usize) {
+        let mut playback = self.playback.lock().unwrap();
+        playback.position = chunk.saturating_sub(HISTORY_CHUNKS);
+        playback.fast_chunks = chunk + 1 - playback.position;
+    }
+
+    /// Analyzes the next chunk while paused.
+    pub fn step(&self) {
+        self.playback.lock().unwrap().fast_chunks += 1;
+    }
+
+    /// Applies the options recorded for the current position.
+    pub fn sync_options(&self) {
+        let options = self.session.options_at(self.current());
+        *self.analyzer.options.write().unwrap() = options;
+    }
+}
+
+/// Formats a Unix time as `YYYYMMDD-HHMMSS` in UTC.
+fn session_id(unix_seconds: u64) -> String {
+    let days = (unix_seconds / 86400) as i64;
+    let seconds = unix_seconds % 86400;
+    // Converts days since 1970-01-01 to a civil date, counting years from March
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_session_id() {
+        assert_eq!(session_id(0), "19700101-000000");
+        assert_eq!(session_id(951_782_400 + 3661), "20000229-010101");
+        assert_eq!(session_id(1_792_281_599), "20261017-235959");
+    }
+
+    #[test]
+    fn test_session_collision() {
+        let dir = crate::utils::temp_path("session-collision");
+        let first = SessionRecorder::new(&dir).unwrap();
+        let second = SessionRecorder::new(&dir).unwrap();
+        let third = SessionRecorder::new(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(first.id, second.id);
+        assert_ne!(second.id, third.id);
+        assert_ne!(first.id, third.id);
+    }
+
+    #[test]
+    fn test_session_round_trip() {
+        let dir = crate::utils::temp_path("session");
+        let mut recorder = SessionRecorder::new(&dir).unwrap();
+        let input = Sound {
+            samples: vec![0.25; CHUNK_SIZE],
+            samplerate: 48000,
+        };
+        let low_gain = AnalyzerOptions::default();
+        let high_gain = AnalyzerOptions {
+            gain: 6.0,
+            ..low_gain
+        };
+        for (i, options) in [low_gain, low_gain, high_gain].iter().enumerate() {
+            let frame = Frame {
+                time: 0.0,
+                rms: 0.25,
+                freq: Some(i as f32 * 100.0),
+                gains: vec![0.5],
+                formants: vec![700.0, 1200.0],
+            };
+            recorder.push(&input, options, frame).unwrap();
+        }
+        let paths = recorder.finish().unwrap();
+        let session = Session::load(&paths.features).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(session.samples, vec![0.25; CHUNK_SIZE * 3]);
+        assert_eq!(session.chunks(), 3);
+        let freqs: Vec<_> = session.frames.iter().map(|f| f.freq).collect();
+        assert_eq!(freqs, [Some(0.0), Some(100.0), Some(200.0)]);
+        assert_eq!(session.frames[1].time, session.seconds_at(1));
+        assert_eq!(session.options_at(1), low_gain);
+        assert_eq!(session.options_at(2), high_gain);
+    }
+}
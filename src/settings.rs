@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use eframe::egui;
+use egui_plot::{PlotBounds, PlotResponse, PlotUi};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::AnalyzerOptions;
+use crate::compare::CompareOptions;
+use crate::osc::OscOptions;
+use crate::overlay::OverlayOptions;
+use crate::profile::Profile;
+use crate::spectrogram::SpectrogramOptions;
+use crate::vowel::VowelSet;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Everything that is kept across sessions, stored as JSON in the user's config directory.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub device_id: Option<String>,
+    /// Switch to the system default device whenever it changes.
+    pub follow_default_device: bool,
+    pub analyzer: AnalyzerOptions,
+    pub osc: OscOptions,
+    /// Streamed from the live analysis and from replays, whether OSC is sent or not.
+    pub overlay: OverlayOptions,
+    pub compare: CompareOptions,
+    /// Profiles saved or imported by the user, in addition to `Profile::builtin`.
+    pub profiles: Vec<Profile>,
+    pub active_profile: Option<String>,
+    pub ui: UiSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiSettings {
+    pub force_show_graph: bool,
+    pub spectrogram: SpectrogramOptions,
+    pub show_vowel_chart: bool,
+    pub vowel_reference: Option<VowelSet>,
+    /// The reference melody file, loaded again on the next start.
+    pub melody_path: Option<PathBuf>,
+    pub layout: Layout,
+}
+
+impl Default for UiSettings {
+    fn default() -> Self {
+        Self {
+            force_show_graph: false,
+            spectrogram: Default::default(),
+            show_vowel_chart: false,
+            vowel_reference: Some(Default::default()),
+            melody_path: None,
+            layout: Default::default(),
+        }
+    }
+}
+
+impl Settings {
+    fn dir() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("APPDATA")?;
+        Some(PathBuf::from(config_dir).join("VoiceAnalyzer"))
+    }
+
+    fn path() -> Option<PathBuf> {
+        Some(Self::dir()?.join("settings.json"))
+    }
+
+    /// Where recorded sessions are written.
+    pub fn sessions_dir() -> Option<PathBuf> {
+        Some(Self::dir()?.join("sessions"))
+    }
+
+    /// Loads the saved settings, or the defaults if there are none yet. Fails if the file exists
+    /// but cannot be read or parsed, leaving it as it is.
+    pub fn load() -> Res<Self> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn load_from(path: &Path) -> Res<Self> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(serde_json::from_str::<Self>(&json)?.validated())
+    }
+
+    /// Moves the settings file that could not be loaded aside, so that saving the defaults does
+    /// not overwrite it. Returns where it was moved to.
+    pub fn back_up() -> Res<PathBuf> {
+        let path = Self::path().ok_or("config directory not found")?;
+        Self::back_up_file(&path)
+    }
+
+    fn back_up_file(path: &Path) -> Res<PathBuf> {
+        let backup = path.with_extension("json.bak");
+        std::fs::rename(path, &backup)?;
+        Ok(backup)
+    }
+
+    /// Replaces analyzer options the analyzer cannot run with by the defaults, drops such
+    /// profiles, clamps the OSC options and drops invalid mappings, so that an edited settings
+    /// file does not crash or silently stop the app.
+    fn validated(mut self) -> Self {
+        if self.analyzer.validate().is_err() {
+            self.analyzer = Default::default();
+        }
+        self.profiles.retain(|p| p.validate().is_ok());
+        self.osc.clamp();
+        if self.osc.mapping.validate().is_err() {
+            self.osc.mapping = Default::default();
+        }
+        let mappings = &mut self.osc.avatar.mappings;
+        mappings.retain(|_, mapping| mapping.validate().is_ok());
+        self
+    }
+
+    pub fn all_profiles(&self) -> Vec<Profile> {
+        let mut profiles = Profile::builtin();
+        profiles.extend(self.profiles.iter().cloned());
+        profiles
+    }
+
+    pub fn find_profile(&self, name: &str) -> Option<Profile> {
+        self.all_profiles().into_iter().find(|p| p.name == name)
+    }
+
+    pub fn is_builtin_profile(name: &str) -> bool {
+        Profile::builtin().iter().any(|p| p.name == name)
+    }
+
+    /// Switches to the profile named `name`, and to its mapping if it has one. The input gain and
+    /// channel are kept as they depend on the device rather than on what is analyzed.
+    pub fn apply_profile(&mut self, name: &str) -> bool {
+        let Some(profile) = self.find_profile(name) else {
+            return false;
+        };
+        self.analyzer = self.device_options_kept(profile.options);
+        if let Some(mapping) = profile.mapping {
+            self.osc.mapping = mapping;
+        }
+        self.active_profile = Some(profile.name);
+        true
+    }
+
+    /// The current analyzer options and mapping as a profile named `name`.
+    pub fn current_profile(&self, name: &str) -> Profile {
+        Profile {
+            mapping: Some(self.osc.mapping.clone()),
+            ..Profile::new(name, self.analyzer)
+        }
+    }
+
+    /// Whether the options have been changed since the active profile was applied.
+    pub fn is_profile_modified(&self) -> bool {
+        self.active_profile
+            .as_deref()
+            .and_then(|name| self.find_profile(name))
+            .is_some_and(|p| {
+                self.device_options_kept(p.options) != self.analyzer
+                    || p.mapping.is_some_and(|mapping| mapping != self.osc.mapping)
+            })
+    }
+
+    fn device_options_kept(&self, options: AnalyzerOptions) -> AnalyzerOptions {
+        AnalyzerOptions {
+            gain: self.analyzer.gain,
+            channel_mode: self.analyzer.channel_mode,
+            ..options
+        }
+    }
+
+    /// Adds `profile`, replacing a user profile with the same name. Built-in profiles cannot be
+    /// replaced.
+    pub fn add_profile(&mut self, profile: Profile) -> bool {
+        if Self::is_builtin_profile(&profile.name) {
+            return false;
+        }
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(p) => *p = profile,
+            None => self.profiles.push(profile),
+        }
+        true
+    }
+
+    pub fn delete_profile(&mut self, name: &str) {
+        self.profiles.retain(|p| p.name != name);
+        if self.active_profile.as_deref() == Some(name) {
+            self.active_profile = None;
+        }
+    }
+
+    pub fn save(&self) -> Res<()> {
+        let path = Self::path().ok_or("config directory not found")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Sizes of the resizable panels and the bounds of the plots the user has moved or zoomed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Layout {
+    panels: BTreeMap<String, f32>,
+    plots: BTreeMap<String, [f64; 4]>,
+    #[serde(skip)]
+    restored_plots: BTreeSet<String>,
+}
+
+impl PartialEq for Layout {
+    fn eq(&self, other: &Self) -> bool {
+        self.panels == other.panels && self.plots == other.plots
+    }
+}
+
+impl Layout {
+    pub fn panel_height(&self, id: &str, default: f32) -> f32 {
+        self.panels.get(id).copied().unwrap_or(default)
+    }
+
+    pub fn set_panel_height(&mut self, id: &str, height: f32) {
+        self.panels.insert(id.to_owned(), height.round());
+    }
+
+    /// Applies the saved bounds of the plot the first time it is shown.
+    pub fn restore_plot(&mut self, id: &str, plot_ui: &mut PlotUi) {
+        if !self.restored_plots.insert(id.to_owned()) {
+            return;
+        }
+        if let Some([min_x, min_y, max_x, max_y]) = self.plots.get(id) {
+            plot_ui.set_plot_bounds(PlotBounds::from_min_max([*min_x, *min_y], [*max_x, *max_y]));
+        }
+    }
+
+    /// Saves the bounds of the plot when the user moves or zooms it, and forgets them when the
+    /// user resets it with a double click.
+    pub fn remember_plot<R>(&mut self, id: &str, plot: &PlotResponse<R>) {
+        let response = &plot.response;
+        if response.double_clicked() {
+            self.plots.remove(id);
+            return;
+        }
+        let scrolled = response.hovered()
+            && response
+                .ctx
+                .input(|i| i.zoom_delta() != 1.0 || i.smooth_scroll_delta != egui::Vec2::ZERO);
+        if response.dragged() || scrolled {
+            let bounds = plot.transform.bounds();
+            let ([min_x, min_y], [max_x, max_y]) = (bounds.min(), bounds.max());
+            self.plots
+                .insert(id.to_owned(), [min_x, min_y, max_x, max_y]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapping::Mapping;
+    #[test]
+    fn test_partial_settings() {
+        let json = r#"{ "device_id": "mic", "analyzer": { "gain": 6.0 } }"#;
+        let settings: Settings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.device_id.as_deref(), Some("mic"));
+        assert_eq!(settings.analyzer.gain, 6.0);
+        assert_eq!(
+            settings.analyzer.harmonics,
+            AnalyzerOptions::default().harmonics
+        );
+        assert_eq!(settings.ui, UiSettings::default());
+
+        let json = serde_json::to_string(&settings).unwrap();
+        assert_eq!(serde_json::from_str::<Settings>(&json).unwrap(), settings);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let json = r#"{
+            "analyzer": { "gain": 6.0, "fft_size": 100 },
+            "osc": { "max_rate": 0.0, "max_payload": 10, "chatbox": { "interval": 1e20 } },
+            "profiles": [
+                { "name": "Good", "options": {} },
+                { "name": "Bad", "options": { "lpc_order": 0 } }
+            ]
+        }"#;
+        let settings = serde_json::from_str::<Settings>(json).unwrap().validated();
+        assert_eq!(settings.analyzer, AnalyzerOptions::default());
+        let names: Vec<&str> = settings.profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Good"]);
+        assert_eq!(settings.osc.max_rate, Some(1.0));
+        assert_eq!(settings.osc.max_payload, 128);
+        assert_eq!(settings.osc.chatbox.interval, 60.0);
+    }
+
+    #[test]
+    fn test_unreadable_settings() {
+        let path = crate::utils::temp_path("settings.json");
+        assert_eq!(Settings::load_from(&path).unwrap(), Settings::default());
+        std::fs::write(&path, r#"{ "device_id": 1 }"#).unwrap();
+        assert!(Settings::load_from(&path).is_err());
+        let backup = Settings::back_up_file(&path).unwrap();
+        let moved = std::fs::read_to_string(&backup);
+        std::fs::remove_file(&backup).unwrap();
+        assert!(!path.exists());
+        assert_eq!(moved.unwrap(), r#"{ "device_id": 1 }"#);
+    }
+
+    #[test]
+    fn test_profile_mapping() {
+        let mut settings = Settings::default();
+        let mapping = Mapping {
+            rules: settings.osc.mapping.rules[..1].to_vec(),
+        };
+        settings.add_profile(Profile {
+            mapping: Some(mapping.clone()),
+            ..Profile::new("Mapped", Default::default())
+        });
+        assert!(settings.apply_profile("Mapped"));
+        assert_eq!(settings.osc.mapping, mapping);
+        assert!(!settings.is_profile_modified());
+        settings.osc.mapping = Default::default();
+        assert!(settings.is_profile_modified());
+        // Built-in profiles leave the mapping as it is
+        assert!(settings.apply_profile("Male speech"));
+        assert_eq!(settings.osc.mapping, Mapping::default());
+    }
+}
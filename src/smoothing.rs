@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mapping::{Group, Output};
+
+/// Cutoff of the derivative in the one euro filter, the value suggested by its authors.
+const ONE_EURO_D_CUTOFF: f32 = 1.0;
+
+/// How a parameter is smoothed before it is sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Smoothing {
+    #[default]
+    None,
+    /// Exponential moving average, reaching 63% of a step after `time_constant` seconds.
+    Ema { time_constant: f32 },
+    /// Smooths strongly while the value holds still and little while it moves fast.
+    /// <https://gery.casiez.net/1euro/>
+    OneEuro { min_cutoff: f32, beta: f32 },
+    /// Median of the last `window` frames, which removes short spikes.
+    Median { window: usize },
+    /// Moves towards the value by at most `max_rate` per second.
+    SlewRate { max_rate: f32 },
+}
+
+impl Smoothing {
+    /// Every kind with parameters that work for values normalized to 0..1.
+    pub const KINDS: [Self; 5] = [
+        Self::None,
+        Self::Ema {
+            time_constant: 0.05,
+        },
+        Self::OneEuro {
+            min_cutoff: 1.0,
+            beta: 5.0,
+        },
+        Self::Median { window: 5 },
+        Self::SlewRate { max_rate: 2.0 },
+    ];
+
+    pub fn is_same_kind(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// The smoothing of each group of OSC parameters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmoothingOptions {
+    /// `FT` and other parameters sent from the pitch
+    pub pitch: Smoothing,
+    /// `G1`, `G2`, ...
+    pub gains: Smoothing,
+    /// `F1` to `F4` and other parameters sent from a formant
+    pub formants: Smoothing,
+}
+
+/// The state of the smoothing of a single parameter.
+#[derive(Debug, Default)]
+struct Filter {
+    smoothing: Smoothing,
+    last: Option<f32>,
+    /// Smoothed derivative for the one euro filter.
+    derivative: f32,
+    window: VecDeque<f32>,
+}
+
+impl Filter {
+    fn apply(&mut self, x: f32, smoothing: Smoothing, dt: f32) -> f32 {
+        if smoothing != self.smoothing {
+            *self = Self {
+                smoothing,
+                ..Default::default()
+            };
+        }
+        let Some(last) = self.last else {
+            self.last = Some(x);
+            self.window.push_back(x);
+            return x;
+        };
+        let y = match smoothing {
+            Smoothing::None => x,
+            Smoothing::Ema { time_constant } => {
+                let alpha = 1.0 - (-dt / time_constant.max(f32::EPSILON)).exp();
+                last + alpha * (x - last)
+            }
+            Smoothing::OneEuro { min_cutoff, beta } => {
+                let alpha = |cutoff: f32| {
+                    let tau = 1.0 / (2.0 * PI * cutoff);
+                    1.0 / (1.0 + tau / dt)
+                };
+                let derivative = (x - last) / dt;
+                self.derivative += alpha(ONE_EURO_D_CUTOFF) * (derivative - self.derivative);
+                let cutoff = min_cutoff + beta * self.derivative.abs();
+                last + alpha(cutoff) * (x - last)
+            }
+            Smoothing::Median { window } => {
+                self.window.push_back(x);
+                while self.window.len() > window.max(1) {
+                    self.window.pop_front();
+                }
+                let mut values: Vec<f32> = self.window.iter().copied().collect();
+                values.sort_by(f32::total_cmp);
+                values[values.len() / 2]
+            }
+            Smoothing::SlewRate { max_rate } => {
+                let max_step = max_rate * dt;
+                last + (x - last).clamp(-max_step, max_step)
+            }
+        };
+        self.last = Some(y);
+        y
+    }
+
+    fn reset(&mut self) {
+        *self = Self {
+            smoothing: self.smoothing,
+            ..Default::default()
+        };
+    }
+}
+
+/// Smooths the values of every OSC parameter, frame by frame.
+#[derive(Default)]
+pub struct Smoother {
+    filters: HashMap<String, Filter>,
+}
+
+impl Smoother {
+    /// Each output is smoothed with the options of its group. Values standing in for a missing
+    /// source, such as the pitch while silent, are passed through and restart the smoothing so
+    /// that the pitch does not glide in from the last note; so do outputs not sent in a frame.
+    /// `dt` is the time since the last frame in seconds.
+    pub fn apply(&mut self, outputs: &mut [Output], options: &SmoothingOptions, dt: f32) {
+        let mut filters = HashMap::with_capacity(outputs.len());
+        for output in outputs.iter_mut() {
+            let mut filter = self.filters.remove(&output.address).unwrap_or_default();
+            let smoothing = match output.group {
+                Group::Pitch => options.pitch,
+                Group::Gains => options.gains,
+                Group::Formants => options.formants,
+                Group::Other => Smoothing::None,
+            };
+            if output.missing {
+                filter.reset();
+            } else {
+                output.value = filter.apply(output.value, smoothing, dt);
+            }
+            filters.insert(output.address.clone(), filter);
+        }
+        self.filters = filters;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapping::Encoding;
+
+    fn run(smoothing: Smoothing, input: &[f32]) -> Vec<f32> {
+        let mut filter = Filter::default();
+        input
+            .iter()
+            .map(|&x| filter.apply(x, smoothing, 0.02))
+            .collect()
+    }
+
+    #[test]
+    fn test_filters() {
+        let step = [0.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+        let ema = run(Smoothing::KINDS[1], &step);
+        assert!(ema.windows(2).all(|w| w[0] < w[1]) && ema[5] < 1.0);
+        let slew = run(Smoothing::SlewRate { max_rate: 12.5 }, &step);
+        assert_eq!(slew, [0.0, 0.25, 0.5, 0.75, 1.0, 1.0]);
+        let spike = run(Smoothing::Median { window: 3 }, &[0.5, 0.5, 1.0, 0.5, 0.5]);
+        assert_eq!(spike, [0.5, 0.5, 0.5, 0.5, 0.5]);
+        let one_euro = run(Smoothing::KINDS[2], &[0.5; 10]);
+        assert_eq!(one_euro, [0.5; 10]);
+        assert_eq!(run(Smoothing::None, &step), step);
+    }
+
+    #[test]
+    fn test_unvoiced_resets_pitch() {
+        let mut smoother = Smoother::default();
+        let options = SmoothingOptions {
+            pitch: Smoothing::SlewRate { max_rate: 1.0 },
+            ..Default::default()
+        };
+        let mut apply = |value, missing| {
+            let mut outputs = [Output {
+                address: "/avatar/parameters/FT".into(),
+                value,
+                encoding: Encoding::Split,
+                group: Group::Pitch,
+                missing,
+            }];
+            smoother.apply(&mut outputs, &options, 0.02);
+            outputs[0].value
+        };
+        assert_eq!(apply(0.2, false), 0.2);
+        assert_eq!(apply(-1.0, true), -1.0);
+        assert_eq!(apply(0.8, false), 0.8);
+    }
+}
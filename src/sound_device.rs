@@ -1,22 +1,69 @@
-use std::{collections::VecDeque, sync::mpsc, thread};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{collections::VecDeque, fmt, sync::mpsc, thread};
 
-type Res<T> = Result<T, Box<dyn std::error::Error>>;
+use serde::{Deserialize, Serialize};
+
+/// How long to wait for the device to deliver samples before treating it as lost.
+const EVENT_TIMEOUT_MS: u32 = 2000;
+/// Interval between attempts to reopen a lost device.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum DeviceError {
+    /// No capture device with the id is connected.
+    NotFound(String),
+    /// The device stopped delivering samples.
+    Timeout,
+    Wasapi(wasapi::WasapiError),
+    Wav(hound::Error),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "device not found: {id}"),
+            Self::Timeout => write!(f, "device stopped delivering samples"),
+            Self::Wasapi(e) => write!(f, "{e}"),
+            Self::Wav(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<wasapi::WasapiError> for DeviceError {
+    fn from(e: wasapi::WasapiError) -> Self {
+        match e {
+            wasapi::WasapiError::EventTimeout => Self::Timeout,
+            e => Self::Wasapi(e),
+        }
+    }
+}
+
+impl From<hound::Error> for DeviceError {
+    fn from(e: hound::Error) -> Self {
+        Self::Wav(e)
+    }
+}
+
+type Res<T> = Result<T, DeviceError>;
 
 fn get_wasapi_devices() -> Res<Vec<wasapi::Device>> {
     let direction = &wasapi::Direction::Capture;
     let devices = wasapi::DeviceCollection::new(direction)?
         .into_iter()
-        .map(|device| device.unwrap())
-        .collect();
+        .collect::<Result<_, _>>()?;
     Ok(devices)
 }
 
 fn get_wasapi_device(device_id: &str) -> Res<wasapi::Device> {
-    let device = get_wasapi_devices()?
+    get_wasapi_devices()?
         .into_iter()
-        .find(|device| device.get_id().unwrap() == device_id)
-        .unwrap();
-    Ok(device)
+        .find(|device| device.get_id().is_ok_and(|id| id == device_id))
+        .ok_or_else(|| DeviceError::NotFound(device_id.to_owned()))
 }
 
 fn get_default_device_id() -> Res<String> {
@@ -31,18 +78,27 @@ pub struct Device {
     pub id: String,
     pub name: String,
     pub samplerate: usize,
+    /// Number of channels the device captures natively.
+    pub channels: usize,
+    /// False once the device has been removed from the system.
+    pub connected: bool,
 }
 
-impl From<&wasapi::Device> for Device {
-    fn from(device: &wasapi::Device) -> Self {
-        let audio_client = device.get_iaudioclient().unwrap();
-        let mixformat = audio_client.get_mixformat().unwrap();
+impl TryFrom<&wasapi::Device> for Device {
+    type Error = DeviceError;
+
+    fn try_from(device: &wasapi::Device) -> Res<Self> {
+        let audio_client = device.get_iaudioclient()?;
+        let mixformat = audio_client.get_mixformat()?;
         let samplerate = mixformat.get_samplespersec() as usize;
-        Self {
-            id: device.get_id().unwrap(),
-            name: device.get_friendlyname().unwrap(),
+        let channels = (mixformat.get_nchannels() as usize).max(1);
+        Ok(Self {
+            id: device.get_id()?,
+            name: device.get_friendlyname()?,
             samplerate,
-        }
+            channels,
+            connected: true,
+        })
     }
 }
 
@@ -55,24 +111,57 @@ impl Device {
 pub struct DeviceList {
     pub devices: Vec<Device>,
     pub index: usize,
+    default_id: Option<String>,
 }
 
 impl DeviceList {
+    /// Lists the connected devices and selects the system default.
     pub fn new() -> Self {
-        let devices: Vec<Device> = get_wasapi_devices()
-            .unwrap()
-            .iter()
-            .map(Into::into)
-            .collect();
-        let default_device_id = get_default_device_id().unwrap();
-        let index = devices
-            .iter()
-            .position(|device| device.id == default_device_id)
-            .unwrap();
-        Self { devices, index }
+        let mut list = Self {
+            devices: vec![],
+            index: 0,
+            default_id: None,
+        };
+        list.refresh();
+        if let Some(default_id) = list.default_id.clone() {
+            list.select(&default_id);
+        }
+        list
     }
-    pub fn device(&self) -> &Device {
-        &self.devices[self.index]
+    pub fn device(&self) -> Option<&Device> {
+        self.devices.get(self.index)
+    }
+    pub fn default_id(&self) -> Option<&str> {
+        self.default_id.as_deref()
+    }
+    /// Selects the device with `id`, keeping the current selection if it is not connected.
+    pub fn select(&mut self, id: &str) -> bool {
+        let index = self.devices.iter().position(|device| device.id == id);
+        if let Some(index) = index {
+            self.index = index;
+        }
+        index.is_some()
+    }
+    /// Updates the list with the devices currently connected. Removed devices stay in the
+    /// list flagged as disconnected, so the selection does not move to another device.
+    pub fn refresh(&mut self) {
+        let Ok(wasapi_devices) = get_wasapi_devices() else {
+            return;
+        };
+        let ids: Vec<Option<String>> = wasapi_devices.iter().map(|d| d.get_id().ok()).collect();
+        for device in self.devices.iter_mut() {
+            device.connected = ids.iter().flatten().any(|id| *id == device.id);
+        }
+        for (wasapi_device, id) in wasapi_devices.iter().zip(ids) {
+            let known = id.is_some_and(|id| self.devices.iter().any(|d| d.id == id));
+            if !known {
+                // Devices that cannot be opened yet are picked up by a later refresh
+                if let Ok(device) = Device::try_from(wasapi_device) {
+                    self.devices.push(device);
+                }
+            }
+        }
+        self.default_id = get_default_device_id().ok();
     }
 }
 
@@ -81,16 +170,102 @@ pub struct Sound {
     pub samplerate: usize,
 }
 
+/// Reads every channel of a WAV file as samples between -1 and 1.
+pub fn read_wav(path: &Path) -> Res<(Vec<Vec<f32>>, usize)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let channel_count = spec.channels.max(1) as usize;
+    let mut channels = vec![Vec::with_capacity(samples.len() / channel_count); channel_count];
+    for frame in samples.chunks_exact(channel_count) {
+        for (channel, &s) in channels.iter_mut().zip(frame) {
+            channel.push(s);
+        }
+    }
+    if channels[0].is_empty() {
+        return Err(hound::Error::FormatError("no samples").into());
+    }
+    Ok((channels, spec.sample_rate as usize))
+}
+
+/// How the channels of a device are turned into the single channel that is analyzed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelMode {
+    /// Average of all channels.
+    #[default]
+    Sum,
+    /// The channel with the most energy in each chunk.
+    MaxEnergy,
+    /// A single channel, counted from 0.
+    Channel(usize),
+}
+
+/// A chunk of samples of every channel of a device.
+pub struct Chunk {
+    pub channels: Vec<Vec<f32>>,
+    pub samplerate: usize,
+}
+
+impl Chunk {
+    pub fn downmix(&self, mode: ChannelMode) -> Sound {
+        let samples = match mode {
+            ChannelMode::Sum => {
+                let scale = 1.0 / self.channels.len() as f32;
+                let mut samples = vec![0.0; self.len()];
+                for channel in &self.channels {
+                    for (sum, s) in samples.iter_mut().zip(channel) {
+                        *sum += s * scale;
+                    }
+                }
+                samples
+            }
+            ChannelMode::MaxEnergy => {
+                let energy = |channel: &&Vec<f32>| channel.iter().map(|s| s * s).sum::<f32>();
+                let loudest = self
+                    .channels
+                    .iter()
+                    .max_by(|a, b| energy(a).total_cmp(&energy(b)));
+                loudest.cloned().unwrap_or_default()
+            }
+            ChannelMode::Channel(i) => {
+                // The device may have fewer channels than when the mode was chosen
+                let i = i.min(self.channels.len() - 1);
+                self.channels[i].clone()
+            }
+        };
+        Sound {
+            samples,
+            samplerate: self.samplerate,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+}
+
+/// Captures until the receiver is dropped. Returns an error when the device is lost.
 fn capture_loop(
-    device: Device,
-    tx: mpsc::SyncSender<Sound>,
+    device: &Device,
+    tx: &mpsc::SyncSender<Chunk>,
     samplerate: usize,
+    channels: usize,
     chunksize: usize,
+    error: &Mutex<Option<DeviceError>>,
 ) -> Res<()> {
     let device = get_wasapi_device(&device.id)?;
     let mut audio_client = device.get_iaudioclient()?;
     let sample_type = &wasapi::SampleType::Float;
-    let desired_format = wasapi::WaveFormat::new(32, 32, sample_type, samplerate, 1, None);
+    let desired_format = wasapi::WaveFormat::new(32, 32, sample_type, samplerate, channels, None);
     let blockalign = desired_format.get_blockalign();
     let (_def_time, min_time) = audio_client.get_device_period()?;
     let mode = wasapi::StreamMode::EventsShared {
@@ -105,43 +280,217 @@ fn capture_loop(
     let mut sample_queue =
         VecDeque::with_capacity(100 * blockalign as usize * (1024 + 2 * buffer_size as usize));
     audio_client.start_stream()?;
+    error.lock().unwrap().take();
     loop {
         let mut stopped = false;
         while sample_queue.len() > (blockalign as usize * chunksize) {
-            let mut chunk = vec![0f32; chunksize];
-            for element in chunk.iter_mut() {
-                let v: Vec<u8> = sample_queue.drain(0..4).collect();
-                *element = f32::from_le_bytes(v.try_into().unwrap());
+            let mut chunk = vec![Vec::with_capacity(chunksize); channels];
+            for _ in 0..chunksize {
+                for channel in chunk.iter_mut() {
+                    let v: Vec<u8> = sample_queue.drain(0..4).collect();
+                    channel.push(f32::from_le_bytes(v.try_into().unwrap()));
+                }
             }
-            let sound = Sound {
-                samples: chunk,
+            let chunk = Chunk {
+                channels: chunk,
                 samplerate,
             };
-            if tx.send(sound).is_err() {
+            if tx.send(chunk).is_err() {
                 stopped = true;
                 break;
             }
         }
-        capture_client.read_from_device_to_deque(&mut sample_queue)?;
-        if stopped || h_event.wait_for_event(30_000).is_err() {
+        if stopped {
             audio_client.stop_stream()?;
             break;
         }
+        capture_client.read_from_device_to_deque(&mut sample_queue)?;
+        h_event.wait_for_event(EVENT_TIMEOUT_MS)?;
     }
     Ok(())
 }
 
 pub struct Capturer {
-    pub rx: mpsc::Receiver<Sound>,
+    pub rx: mpsc::Receiver<Chunk>,
+    error: Arc<Mutex<Option<DeviceError>>>,
+    stop: Arc<AtomicBool>,
 }
 
 impl Capturer {
+    /// Starts capturing on a background thread, which keeps reopening the device while it is
+    /// lost until the capturer is dropped.
     fn new(device: Device, chunksize: usize) -> Self {
         let (tx, rx) = mpsc::sync_channel(1);
+        let error = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_error, thread_stop) = (error.clone(), stop.clone());
+        thread::spawn(move || {
+            let (samplerate, channels) = (device.samplerate, device.channels);
+            while !thread_stop.load(Ordering::Relaxed) {
+                let result =
+                    capture_loop(&device, &tx, samplerate, channels, chunksize, &thread_error);
+                match result {
+                    Ok(()) => break,
+                    Err(e) => {
+                        *thread_error.lock().unwrap() = Some(e);
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            }
+        });
+        Self { rx, error, stop }
+    }
+
+    /// Plays the WAV file at `path` in real time as if it were captured, repeating it until the
+    /// capturer is dropped.
+    pub fn from_file(path: &Path, chunksize: usize) -> Res<Self> {
+        let (channels, samplerate) = read_wav(path)?;
+        let (tx, rx) = mpsc::sync_channel(1);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            let chunk_duration = Duration::from_secs_f64(chunksize as f64 / samplerate as f64);
+            let len = channels[0].len();
+            let started_at = Instant::now();
+            for (i, start) in (0..len).step_by(chunksize).cycle().enumerate() {
+                let end = (start + chunksize).min(len);
+                let chunk = Chunk {
+                    channels: channels
+                        .iter()
+                        .map(|channel| {
+                            let mut samples = channel[start..end].to_vec();
+                            samples.resize(chunksize, 0.0);
+                            samples
+                        })
+                        .collect(),
+                    samplerate,
+                };
+                if thread_stop.load(Ordering::Relaxed) || tx.send(chunk).is_err() {
+                    break;
+                }
+                let next = started_at + chunk_duration * (i as u32 + 1);
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+        });
+        Ok(Self {
+            rx,
+            error: Default::default(),
+            stop,
+        })
+    }
+
+    /// Plays mono `samples` as if they were captured, from the chunk and at the speed set in
+    /// `playback`, until the capturer is dropped.
+    pub fn from_playback(
+        samples: Vec<f32>,
+        samplerate: usize,
+        chunksize: usize,
+        playback: Arc<Mutex<Playback>>,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
         thread::spawn(move || {
-            let samplerate = device.samplerate;
-            capture_loop(device, tx, samplerate, chunksize).unwrap();
+            let chunk_duration = Duration::from_secs_f64(chunksize as f64 / samplerate as f64);
+            let chunks = samples.len() / chunksize;
+            let mut next = Instant::now();
+            while !thread_stop.load(Ordering::Relaxed) {
+                let (position, speed) = {
+                    let mut playback = playback.lock().unwrap();
+                    if playback.position >= chunks {
+                        playback.playing = false;
+                        playback.fast_chunks = 0;
+                    }
+                    let fast = playback.fast_chunks > 0;
+                    if !fast && !playback.playing {
+                        drop(playback);
+                        thread::sleep(chunk_duration);
+                        next = Instant::now();
+                        continue;
+                    }
+                    let position = playback.position;
+                    playback.position += 1;
+                    if fast {
+                        playback.fast_chunks -= 1;
+                    }
+                    (position, (!fast).then_some(playback.speed))
+                };
+                let start = position * chunksize;
+                let chunk = Chunk {
+                    channels: vec![samples[start..start + chunksize].to_vec()],
+                    samplerate,
+                };
+                if tx.send(chunk).is_err() {
+                    break;
+                }
+                if let Some(speed) = speed {
+                    next = next.max(Instant::now()) + chunk_duration.div_f32(speed);
+                    thread::sleep(next.saturating_duration_since(Instant::now()));
+                }
+            }
         });
-        Self { rx }
+        Self {
+            rx,
+            error: Default::default(),
+            stop,
+        }
+    }
+
+    /// The reason the device is not delivering samples, if it has been lost.
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().as_ref().map(ToString::to_string)
+    }
+}
+
+impl Drop for Capturer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where and how fast a recording is replayed by `Capturer::from_playback`.
+#[derive(Debug, Clone, Copy)]
+pub struct Playback {
+    /// The next chunk to play.
+    pub position: usize,
+    pub playing: bool,
+    pub speed: f32,
+    /// Chunks to play without waiting, whether playing or not.
+    pub fast_chunks: usize,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            position: 0,
+            playing: true,
+            speed: 1.0,
+            fast_chunks: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_downmix() {
+        let chunk = Chunk {
+            channels: vec![vec![0.5, -0.5], vec![0.25, 0.75]],
+            samplerate: 48000,
+        };
+        assert_eq!(chunk.downmix(ChannelMode::Sum).samples, vec![0.375, 0.125]);
+        assert_eq!(
+            chunk.downmix(ChannelMode::MaxEnergy).samples,
+            vec![0.25, 0.75]
+        );
+        assert_eq!(
+            chunk.downmix(ChannelMode::Channel(1)).samples,
+            vec![0.25, 0.75]
+        );
+        assert_eq!(
+            chunk.downmix(ChannelMode::Channel(5)).samples,
+            vec![0.25, 0.75]
+        );
     }
 }
@@ -0,0 +1,167 @@
+use eframe::egui::{self, Color32, ColorImage, TextureHandle, TextureOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{SpectrumHistory, SPECTROGRAM_MAX_FREQ};
+use crate::utils;
+
+const IMAGE_HEIGHT: usize = 256;
+const MIN_LOG_FREQ: f32 = 50.0;
+const DB_RANGE: (f32, f32) = (0.0, 60.0);
+
+const MAGMA: [Color32; 6] = [
+    Color32::from_rgb(0x00, 0x00, 0x04),
+    Color32::from_rgb(0x3b, 0x0f, 0x70),
+    Color32::from_rgb(0x8c, 0x29, 0x81),
+    Color32::from_rgb(0xde, 0x49, 0x68),
+    Color32::from_rgb(0xfe, 0x9f, 0x6d),
+    Color32::from_rgb(0xfc, 0xfd, 0xbf),
+];
+const VIRIDIS: [Color32; 5] = [
+    Color32::from_rgb(0x44, 0x01, 0x54),
+    Color32::from_rgb(0x3b, 0x52, 0x8b),
+    Color32::from_rgb(0x21, 0x91, 0x8c),
+    Color32::from_rgb(0x5e, 0xc9, 0x62),
+    Color32::from_rgb(0xfd, 0xe7, 0x25),
+];
+const JET: [Color32; 6] = [
+    Color32::from_rgb(0x00, 0x00, 0x7f),
+    Color32::from_rgb(0x00, 0x00, 0xff),
+    Color32::from_rgb(0x00, 0xff, 0xff),
+    Color32::from_rgb(0xff, 0xff, 0x00),
+    Color32::from_rgb(0xff, 0x00, 0x00),
+    Color32::from_rgb(0x7f, 0x00, 0x00),
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Colormap {
+    #[default]
+    Magma,
+    Viridis,
+    Jet,
+    Grayscale,
+}
+
+impl Colormap {
+    pub const ALL: [Self; 4] = [Self::Magma, Self::Viridis, Self::Jet, Self::Grayscale];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Magma => "Magma",
+            Self::Viridis => "Viridis",
+            Self::Jet => "Jet",
+            Self::Grayscale => "Grayscale",
+        }
+    }
+
+    fn stops(&self) -> &'static [Color32] {
+        match self {
+            Self::Magma => &MAGMA,
+            Self::Viridis => &VIRIDIS,
+            Self::Jet => &JET,
+            Self::Grayscale => &[Color32::BLACK, Color32::WHITE],
+        }
+    }
+
+    fn color(&self, t: f32) -> Color32 {
+        let stops = self.stops();
+        let x = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let i = (x as usize).min(stops.len() - 2);
+        stops[i].lerp_to_gamma(stops[i + 1], x - i as f32)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FreqScale {
+    Linear,
+    #[default]
+    Log,
+    Mel,
+}
+
+impl FreqScale {
+    pub const ALL: [Self; 3] = [Self::Linear, Self::Log, Self::Mel];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Linear => "Linear",
+            Self::Log => "Log",
+            Self::Mel => "Mel",
+        }
+    }
+
+    fn warp(&self, freq: f32) -> f32 {
+        match self {
+            Self::Linear => freq,
+            Self::Log => freq.max(MIN_LOG_FREQ).ln(),
+            Self::Mel => 2595.0 * (1.0 + freq / 700.0).log10(),
+        }
+    }
+
+    fn unwarp(&self, v: f32) -> f32 {
+        match self {
+            Self::Linear => v,
+            Self::Log => v.exp(),
+            Self::Mel => 700.0 * (10.0f32.powf(v / 2595.0) - 1.0),
+        }
+    }
+
+    /// Frequency at `y`, a position along the axis from 0.0 (bottom) to 1.0 (top).
+    pub fn freq_at(&self, y: f32) -> f32 {
+        let min = self.warp(0.0);
+        let max = self.warp(SPECTROGRAM_MAX_FREQ);
+        self.unwarp(min + (max - min) * y)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpectrogramOptions {
+    pub visible: bool,
+    pub colormap: Colormap,
+    pub scale: FreqScale,
+}
+
+/// Waterfall rendering of the spectrum history, time on the x axis and frequency on the y axis.
+#[derive(Default)]
+pub struct SpectrogramView {
+    texture: Option<TextureHandle>,
+}
+
+impl SpectrogramView {
+    pub fn update_texture(
+        &mut self,
+        ctx: &egui::Context,
+        history: &SpectrumHistory,
+        options: &SpectrogramOptions,
+    ) -> &TextureHandle {
+        let image = render(history, options);
+        match &mut self.texture {
+            Some(texture) => texture.set(image, TextureOptions::LINEAR),
+            None => {
+                let texture = ctx.load_texture("spectrogram", image, TextureOptions::LINEAR);
+                self.texture = Some(texture);
+            }
+        }
+        self.texture.as_ref().unwrap()
+    }
+}
+
+fn render(history: &SpectrumHistory, options: &SpectrogramOptions) -> ColorImage {
+    let width = history.frames.len().max(1);
+    let bins: Vec<usize> = (0..IMAGE_HEIGHT)
+        .map(|row| {
+            let y = 1.0 - (row as f32 + 0.5) / IMAGE_HEIGHT as f32;
+            (options.scale.freq_at(y) / history.freq_step).round() as usize
+        })
+        .collect();
+    let mut image = ColorImage::filled([width, IMAGE_HEIGHT], options.colormap.color(0.0));
+    for (x, frame) in history.frames.iter().enumerate() {
+        for (row, &bin) in bins.iter().enumerate() {
+            let magnitude = frame.get(bin).copied().unwrap_or(0.0);
+            let db = utils::to_db(magnitude);
+            let t = utils::normalize(db, DB_RANGE.0, DB_RANGE.1);
+            image.pixels[row * width + x] = options.colormap.color(t);
+        }
+    }
+    image
+}
@@ -0,0 +1,236 @@
+use std::path::Path;
+
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+
+use crate::analyzer::freq_to_midi_note;
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// A pitch further than this many semitones from the note being sung starts a new note.
+const NOTE_CHANGE_SEMITONES: f32 = 0.7;
+/// Frames a new pitch has to be held before it is taken as a new note rather than a slide
+/// or vibrato.
+const STABLE_FRAMES: usize = 4;
+/// Unvoiced frames that end a note. Shorter gaps are bridged, as the detector drops out now
+/// and then in the middle of a note.
+const GAP_FRAMES: usize = 3;
+const MIN_NOTE_SECONDS: f64 = 0.08;
+/// Semitones of a full pitch bend, the General MIDI default.
+const BEND_RANGE: f32 = 2.0;
+const TICKS_PER_BEAT: u16 = 480;
+/// 120 BPM, so that a beat is half a second.
+const MICROSECONDS_PER_BEAT: u32 = 500_000;
+const VELOCITY: u8 = 96;
+
+/// A note segmented from the pitch track, timed in seconds from the start of the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SungNote {
+    pub start: f64,
+    pub end: f64,
+    pub key: u8,
+    /// (seconds, semitones from `key`) of each voiced frame, for the pitch bend.
+    pub bends: Vec<(f64, f32)>,
+}
+
+/// Collects the pitch of every analyzed frame while recording.
+pub struct PitchRecorder {
+    /// MIDI note number of each frame, `None` where no pitch was detected.
+    frames: Vec<Option<f32>>,
+    frame_period: f64,
+}
+
+impl PitchRecorder {
+    pub fn new() -> Self {
+        Self {
+            frames: vec![],
+            frame_period: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, freq: Option<f32>, frame_period: f64) {
+        self.frames.push(freq.map(|f| freq_to_midi_note(&f)));
+        self.frame_period = frame_period;
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.frames.len() as f64 * self.frame_period
+    }
+
+    pub fn notes(&self) -> Vec<SungNote> {
+        segment(&self.frames, self.frame_period)
+    }
+
+    pub fn save(&self, path: &Path) -> Res<()> {
+        let notes = self.notes();
+        if notes.is_empty() {
+            return Err("no notes were sung".into());
+        }
+        let mut bytes = vec![];
+        write_smf(&notes, &mut bytes)?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+}
+
+/// Splits a pitch track into notes. A note starts where the voice starts or where the pitch
+/// moves to a new note and stays there, and ends where the voice stops.
+fn segment(frames: &[Option<f32>], frame_period: f64) -> Vec<SungNote> {
+    // (first frame, frames) of each note before assigning keys
+    let mut segments: Vec<(usize, Vec<(usize, f32)>)> = vec![];
+    let mut current: Option<(usize, Vec<(usize, f32)>)> = None;
+    let mut unvoiced = 0;
+    for (i, frame) in frames.iter().enumerate() {
+        let Some(pitch) = *frame else {
+            unvoiced += 1;
+            if unvoiced >= GAP_FRAMES {
+                segments.extend(current.take());
+            }
+            continue;
+        };
+        unvoiced = 0;
+        let Some((_, pitches)) = &mut current else {
+            current = Some((i, vec![(i, pitch)]));
+            continue;
+        };
+        pitches.push((i, pitch));
+        let center = median(pitches.iter().map(|p| p.1));
+        let moved = pitches
+            .iter()
+            .rev()
+            .take(STABLE_FRAMES)
+            .all(|p| (p.1 - center).abs() > NOTE_CHANGE_SEMITONES);
+        if pitches.len() > STABLE_FRAMES && moved {
+            let split = pitches.len() - STABLE_FRAMES;
+            let rest = pitches.split_off(split);
+            segments.extend(current.replace((rest[0].0, rest)));
+        }
+    }
+    segments.extend(current);
+    segments
+        .into_iter()
+        .filter_map(|(first, pitches)| {
+            let last = pitches.last()?.0;
+            let start = first as f64 * frame_period;
+            let end = (last + 1) as f64 * frame_period;
+            if end - start < MIN_NOTE_SECONDS {
+                return None;
+            }
+            let key = median(pitches.iter().map(|p| p.1))
+                .round()
+                .clamp(0.0, 127.0);
+            let bends = pitches
+                .iter()
+                .map(|&(i, pitch)| (i as f64 * frame_period, pitch - key))
+                .collect();
+            Some(SungNote {
+                start,
+                end,
+                key: key as u8,
+                bends,
+            })
+        })
+        .collect()
+}
+
+fn median(values: impl Iterator<Item = f32>) -> f32 {
+    let mut values: Vec<f32> = values.collect();
+    values.sort_by(f32::total_cmp);
+    values[values.len() / 2]
+}
+
+/// Writes the notes as a single track Standard MIDI File at 120 BPM, with the sub-semitone
+/// detail as pitch bends on the same channel.
+fn write_smf(notes: &[SungNote], out: &mut Vec<u8>) -> Res<()> {
+    let channel = u4::new(0);
+    let midi = |message| TrackEventKind::Midi { channel, message };
+    let controller = |controller: u8, value: u8| {
+        midi(MidiMessage::Controller {
+            controller: u7::new(controller),
+            value: u7::new(value),
+        })
+    };
+    let bend = |semitones: f32| {
+        let value = (semitones / BEND_RANGE * 8192.0).clamp(-8192.0, 8191.0);
+        midi(MidiMessage::PitchBend {
+            bend: PitchBend::from_int(value as i16),
+        })
+    };
+
+    let tempo = MetaMessage::Tempo(u24::new(MICROSECONDS_PER_BEAT));
+    // Sets the pitch bend range through RPN 0
+    let mut events: Vec<(f64, TrackEventKind)> = vec![
+        (0.0, TrackEventKind::Meta(tempo)),
+        (0.0, controller(101, 0)),
+        (0.0, controller(100, 0)),
+        (0.0, controller(6, BEND_RANGE as u8)),
+        (0.0, controller(38, 0)),
+    ];
+    for note in notes {
+        let key = u7::new(note.key);
+        events.push((note.start, bend(note.bends[0].1)));
+        let vel = u7::new(VELOCITY);
+        events.push((note.start, midi(MidiMessage::NoteOn { key, vel })));
+        for &(seconds, semitones) in &note.bends[1..] {
+            events.push((seconds, bend(semitones)));
+        }
+        let vel = u7::new(0);
+        events.push((note.end, midi(MidiMessage::NoteOff { key, vel })));
+    }
+    let ticks_per_second = TICKS_PER_BEAT as f64 * 1e6 / MICROSECONDS_PER_BEAT as f64;
+    let mut track = vec![];
+    let mut last_tick = 0;
+    for (seconds, kind) in events {
+        let tick = ((seconds * ticks_per_second).round() as u32).max(last_tick);
+        track.push(TrackEvent {
+            delta: u28::new(tick - last_tick),
+            kind,
+        });
+        last_tick = tick;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    let timing = Timing::Metrical(u15::new(TICKS_PER_BEAT));
+    let mut smf = Smf::new(Header::new(Format::SingleTrack, timing));
+    smf.tracks.push(track);
+    smf.write_std(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::melody::Melody;
+
+    #[test]
+    fn test_segment() {
+        let a4 = Some(69.0);
+        let vibrato = [Some(69.3), Some(68.7)];
+        let c5 = Some(72.1);
+        let mut frames = vec![a4; 10];
+        frames.extend(vibrato.repeat(5));
+        // A short dropout does not split the note
+        frames.extend([None, Some(69.0)]);
+        frames.extend(vec![c5; 10]);
+        frames.extend(vec![None; 5]);
+        frames.extend(vec![a4; 2]);
+        let notes = segment(&frames, 0.02);
+        let keys: Vec<(u8, usize)> = notes.iter().map(|n| (n.key, n.bends.len())).collect();
+        assert_eq!(keys, [(69, 21), (72, 10)]);
+        assert!((notes[1].bends[0].1 - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_write_smf() {
+        let notes = segment(&[vec![Some(60.0); 25], vec![Some(64.4); 25]].concat(), 0.02);
+        let mut bytes = vec![];
+        write_smf(&notes, &mut bytes).unwrap();
+        let melody = Melody::from_midi(&bytes).unwrap();
+        let keys: Vec<(f64, u8)> = melody.notes.iter().map(|n| (n.start, n.key)).collect();
+        assert_eq!(keys, [(0.0, 60), (0.5, 64)]);
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::Path;
+
+use crate::analyzer::MAX_HARMONICS;
+use crate::mapping::{Encoding, Group, Mapping};
+
+type Res<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// The renderer of the spectrogram in the prefab, whose material properties are animated.
+pub const DEFAULT_PATH: &str = "Updater/SpectrogramUpdater";
+pub const DEFAULT_NAME: &str = "Spectrogram";
+/// The file IDs Unity gives the main object of a clip and of a controller.
+const CLIP_FILE_ID: i64 = 7400000;
+const CONTROLLER_FILE_ID: i64 = 9100000;
+/// `classID` of `Renderer`, which material properties are animated on.
+const RENDERER_CLASS_ID: u32 = 23;
+/// The shader of the spectrogram material, whose properties the clips animate. Its comments are
+/// not UTF-8.
+const SHADER: &[u8] =
+    include_bytes!("../Assets/VoiceAnalyzer/Material/Shader/GrabPassShader.shader");
+
+/// What to generate the assets for.
+pub struct AssetOptions {
+    pub harmonics: usize,
+    /// Decides the parameter names, so that the assets match what is sent.
+    pub mapping: Mapping,
+    /// Name of the controller.
+    pub name: String,
+    /// Path of the renderer from the root of the animated object.
+    pub path: String,
+}
+
+impl Default for AssetOptions {
+    fn default() -> Self {
+        Self {
+            harmonics: crate::analyzer::DEFAULT_HARMONICS,
+            mapping: Default::default(),
+            name: DEFAULT_NAME.to_owned(),
+            path: DEFAULT_PATH.to_owned(),
+        }
+    }
+}
+
+/// A generated file.
+pub struct Asset {
+    pub file_name: String,
+    pub contents: String,
+}
+
+/// The clips that copy the pitch and gain parameters of the mapping to the material property
+/// of the same name with a leading underscore, such as `G1` to `_G1`, and a controller with a
+/// layer playing each clip with the parameter as its motion time. Every asset gets a fresh
+/// GUID. The layers that toggle the spectrogram are not generated.
+pub fn generate(options: &AssetOptions) -> Res<Vec<Asset>> {
+    let parameters = animated_parameters(options)?;
+    if parameters.is_empty() {
+        return Err("the mapping sends no pitch or gain parameters".into());
+    }
+    let mut assets = vec![];
+    let mut clips = vec![];
+    for parameter in parameters {
+        let name = format!("S_{parameter}");
+        let guid = new_guid();
+        assets.push(Asset {
+            file_name: format!("{name}.anim"),
+            contents: clip(&name, &format!("material._{parameter}"), &options.path),
+        });
+        assets.push(Asset {
+            file_name: format!("{name}.anim.meta"),
+            contents: meta(&guid, CLIP_FILE_ID),
+        });
+        clips.push((parameter, guid));
+    }
+    assets.push(Asset {
+        file_name: format!("{}.controller", options.name),
+        contents: controller(&options.name, &clips),
+    });
+    assets.push(Asset {
+        file_name: format!("{}.controller.meta", options.name),
+        contents: meta(&new_guid(), CONTROLLER_FILE_ID),
+    });
+    Ok(assets)
+}
+
+/// The parameters a clip is generated for.
+fn animated_parameters(options: &AssetOptions) -> Res<Vec<String>> {
+    // The app never sends more, so the clips for them would never move
+    if !(1..=MAX_HARMONICS).contains(&options.harmonics) {
+        return Err(format!("harmonics must be between 1 and {MAX_HARMONICS}").into());
+    }
+    Ok(options
+        .mapping
+        .parameters(options.harmonics)
+        .into_iter()
+        // A motion time is a float in 0..1
+        .filter(|(_, rule)| matches!(rule.source.group(), Group::Pitch | Group::Gains))
+        .filter(|(_, rule)| matches!(rule.encoding, Encoding::Float | Encoding::Split))
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// The material properties the generated clips would animate but the shader does not declare,
+/// such as `_G21` and above, which no error in Unity points out.
+pub fn missing_properties(options: &AssetOptions) -> Res<Vec<String>> {
+    let declared = shader_properties();
+    Ok(animated_parameters(options)?
+        .into_iter()
+        .map(|parameter| format!("_{parameter}"))
+        .filter(|property| !declared.contains(property))
+        .collect())
+}
+
+/// The names in the `Properties` block of the shader, such as `_G1`.
+fn shader_properties() -> Vec<String> {
+    let shader = String::from_utf8_lossy(SHADER);
+    // The block ends at the first line that closes it; comments in it may contain braces
+    shader
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "Properties")
+        .skip(2)
+        .take_while(|line| *line != "}")
+        .filter(|line| line.starts_with('_'))
+        .filter_map(|line| line.split_once('(').map(|(name, _)| name.trim().to_owned()))
+        .collect()
+}
+
+/// Writes `assets` to `dir`, writing nothing if any of the files already exists. The controller
+/// lacks the layers of the shipped one, and the new GUIDs would break what refers to the old
+/// clips, so the shipped assets must not be replaced.
+pub fn write(dir: &Path, assets: &[Asset]) -> Res<()> {
+    if let Some(asset) = assets.iter().find(|a| dir.join(&a.file_name).exists()) {
+        let path = dir.join(&asset.file_name);
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    std::fs::create_dir_all(dir)?;
+    for asset in assets {
+        let mut file = std::fs::File::create_new(dir.join(&asset.file_name))?;
+        file.write_all(asset.contents.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn new_guid() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+/// A local file ID that does not collide with the main objects.
+fn new_file_id() -> i64 {
+    loop {
+        let id = random_u64() as i64;
+        if ![0, CLIP_FILE_ID, CONTROLLER_FILE_ID].contains(&id) {
+            return id;
+        }
+    }
+}
+
+fn meta(guid: &str, main_object_file_id: i64) -> String {
+    format!(
+        "fileFormatVersion: 2
+guid: {guid}
+NativeFormatImporter:
+  externalObjects: {{}}
+  mainObjectFileID: {main_object_file_id}
+  userData:
+  assetBundleName:
+  assetBundleVariant:
+"
+    )
+}
+
+/// A clip moving `attribute` from 0 to 1 over a second.
+fn clip(name: &str, attribute: &str, path: &str) -> String {
+    let curve = format!(
+        "  - serializedVersion: 2
+    curve:
+      serializedVersion: 2
+      m_Curve:
+      - serializedVersion: 3
+        time: 0
+        value: 0
+        inSlope: 0
+        outSlope: 1
+        tangentMode: 69
+        weightedMode: 0
+        inWeight: 0.33333334
+        outWeight: 0.33333334
+      - serializedVersion: 3
+        time: 1
+        value: 1
+        inSlope: 1
+        outSlope: 0
+        tangentMode: 69
+        weightedMode: 0
+        inWeight: 0.33333334
+        outWeight: 0.33333334
+      m_PreInfinity: 2
+      m_PostInfinity: 2
+      m_RotationOrder: 4
+    attribute: {attribute}
+    path: {path}
+    classID: {RENDERER_CLASS_ID}
+    script: {{fileID: 0}}
+    flags: 16
+"
+    );
+    // Unity fills in the binding constant when it imports the clip
+    format!(
+        "%YAML 1.1
+%TAG !u! tag:unity3d.com,2011:
+--- !u!74 &{CLIP_FILE_ID}
+AnimationClip:
+  m_ObjectHideFlags: 0
+  m_CorrespondingSourceObject: {{fileID: 0}}
+  m_PrefabInstance: {{fileID: 0}}
+  m_PrefabAsset: {{fileID: 0}}
+  m_Name: {name}
+  serializedVersion: 7
+  m_Legacy: 0
+  m_Compressed: 0
+  m_UseHighQualityCurve: 1
+  m_RotationCurves: []
+  m_CompressedRotationCurves: []
+  m_EulerCurves: []
+  m_PositionCurves: []
+  m_ScaleCurves: []
+  m_FloatCurves:
+{curve}  m_PPtrCurves: []
+  m_SampleRate: 60
+  m_WrapMode: 0
+  m_Bounds:
+    m_Center: {{x: 0, y: 0, z: 0}}
+    m_Extent: {{x: 0, y: 0, z: 0}}
+  m_ClipBindingConstant:
+    genericBindings: []
+    pptrCurveMapping: []
+  m_AnimationClipSettings:
+    serializedVersion: 2
+    m_AdditiveReferencePoseClip: {{fileID: 0}}
+    m_AdditiveReferencePoseTime: 0
+    m_StartTime: 0
+    m_StopTime: 1
+    m_OrientationOffsetY: 0
+    m_Level: 0
+    m_CycleOffset: 0
+    m_HasAdditiveReferencePose: 0
+    m_LoopTime: 0
+    m_LoopBlend: 0
+    m_LoopBlendOrientation: 0
+    m_LoopBlendPositionY: 0
+    m_LoopBlendPositionXZ: 0
+    m_KeepOriginalOrientation: 0
+    m_KeepOriginalPositionY: 1
+    m_KeepOriginalPositionXZ: 0
+    m_HeightFromFeet: 0
+    m_Mirror: 0
+  m_EditorCurves:
+{curve}  m_EulerEditorCurves: []
+  m_HasGenericRootTransform: 0
+  m_HasMotionFloatCurves: 0
+  m_Events: []
+"
+    )
+}
+
+/// A controller with a float parameter and a layer for each (parameter, clip GUID).
+fn controller(name: &str, clips: &[(String, String)]) -> String {
+    let mut objects = String::new();
+    let mut parameters = String::new();
+    let mut layers = String::new();
+    for (parameter, guid) in clips {
+        let state_machine_id = new_file_id();
+        let state_id = new_file_id();
+        objects += &format!(
+            "--- !u!1107 &{state_machine_id}
+AnimatorStateMachine:
+  serializedVersion: 6
+  m_ObjectHideFlags: 1
+  m_CorrespondingSourceObject: {{fileID: 0}}
+  m_PrefabInstance: {{fileID: 0}}
+  m_PrefabAsset: {{fileID: 0}}
+  m_Name: {parameter}
+  m_ChildStates:
+  - serializedVersion: 1
+    m_State: {{fileID: {state_id}}}
+    m_Position: {{x: 300, y: 120, z: 0}}
+  m_ChildStateMachines: []
+  m_AnyStateTransitions: []
+  m_EntryTransitions: []
+  m_StateMachineTransitions: {{}}
+  m_StateMachineBehaviours: []
+  m_AnyStatePosition: {{x: 50, y: 20, z: 0}}
+  m_EntryPosition: {{x: 50, y: 120, z: 0}}
+  m_ExitPosition: {{x: 800, y: 120, z: 0}}
+  m_ParentStateMachinePosition: {{x: 800, y: 20, z: 0}}
+  m_DefaultState: {{fileID: {state_id}}}
+--- !u!1102 &{state_id}
+AnimatorState:
+  serializedVersion: 6
+  m_ObjectHideFlags: 1
+  m_CorrespondingSourceObject: {{fileID: 0}}
+  m_PrefabInstance: {{fileID: 0}}
+  m_PrefabAsset: {{fileID: 0}}
+  m_Name: S_{parameter}
+  m_Speed: 1
+  m_CycleOffset: 0
+  m_Transitions: []
+  m_StateMachineBehaviours: []
+  m_Position: {{x: 50, y: 50, z: 0}}
+  m_IKOnFeet: 0
+  m_WriteDefaultValues: 0
+  m_Mirror: 0
+  m_SpeedParameterActive: 0
+  m_MirrorParameterActive: 0
+  m_CycleOffsetParameterActive: 0
+  m_TimeParameterActive: 1
+  m_Motion: {{fileID: {CLIP_FILE_ID}, guid: {guid}, type: 2}}
+  m_Tag:
+  m_SpeedParameter:
+  m_MirrorParameter:
+  m_CycleOffsetParameter:
+  m_TimeParameter: {parameter}
+"
+        );
+        parameters += &format!(
+            "  - m_Name: {parameter}
+    m_Type: 1
+    m_DefaultFloat: 0
+    m_DefaultInt: 0
+    m_DefaultBool: 0
+    m_Controller: {{fileID: {CONTROLLER_FILE_ID}}}
+"
+        );
+        layers += &format!(
+            "  - serializedVersion: 5
+    m_Name: {parameter}
+    m_StateMachine: {{fileID: {state_machine_id}}}
+    m_Mask: {{fileID: 0}}
+    m_Motions: []
+    m_Behaviours: []
+    m_BlendingMode: 0
+    m_SyncedLayerIndex: -1
+    m_DefaultWeight: 1
+    m_IKPass: 0
+    m_SyncedLayerAffectsTiming: 0
+    m_Controller: {{fileID: {CONTROLLER_FILE_ID}}}
+"
+        );
+    }
+    format!(
+        "%YAML 1.1
+%TAG !u! tag:unity3d.com,2011:
+{objects}--- !u!91 &{CONTROLLER_FILE_ID}
+AnimatorController:
+  m_ObjectHideFlags: 0
+  m_CorrespondingSourceObject: {{fileID: 0}}
+  m_PrefabInstance: {{fileID: 0}}
+  m_PrefabAsset: {{fileID: 0}}
+  m_Name: {name}
+  serializedVersion: 5
+  m_AnimatorParameters:
+{parameters}  m_AnimatorLayers:
+{layers}"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let options = AssetOptions {
+            harmonics: 3,
+            ..Default::default()
+        };
+        let assets = generate(&options).unwrap();
+        let names: Vec<&str> = assets.iter().map(|a| a.file_name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "S_G1.anim",
+                "S_G1.anim.meta",
+                "S_G2.anim",
+                "S_G2.anim.meta",
+                "S_G3.anim",
+                "S_G3.anim.meta",
+                "S_FT_L.anim",
+                "S_FT_L.anim.meta",
+                "S_FT_H.anim",
+                "S_FT_H.anim.meta",
+                "Spectrogram.controller",
+                "Spectrogram.controller.meta",
+            ]
+        );
+        assert!(assets[0].contents.contains("attribute: material._G1\n"));
+        let guids: Vec<&str> = assets
+            .iter()
+            .filter_map(|a| a.contents.strip_prefix("fileFormatVersion: 2\nguid: "))
+            .map(|rest| &rest[..32])
+            .collect();
+        assert_eq!(guids.len(), 6);
+        assert!(guids
+            .iter()
+            .all(|g| g.bytes().all(|b| b.is_ascii_hexdigit())));
+        let controller = &assets[10].contents;
+        for guid in &guids[..5] {
+            assert!(controller.contains(&format!("guid: {guid}, type: 2}}")));
+        }
+        assert_eq!(controller.matches("m_TimeParameter: ").count(), 5);
+        let mut unique = guids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), guids.len());
+
+        let no_spectrogram = AssetOptions {
+            mapping: Mapping { rules: vec![] },
+            ..Default::default()
+        };
+        assert!(generate(&no_spectrogram).is_err());
+        for harmonics in [0, MAX_HARMONICS + 1] {
+            let options = AssetOptions {
+                harmonics,
+                ..Default::default()
+            };
+            assert!(generate(&options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_missing_properties() {
+        let options = |harmonics| AssetOptions {
+            harmonics,
+            ..Default::default()
+        };
+        assert_eq!(shader_properties().len(), 23);
+        assert!(missing_properties(&options(20)).unwrap().is_empty());
+        assert_eq!(missing_properties(&options(22)).unwrap(), ["_G21", "_G22"]);
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = crate::utils::temp_path("unity");
+        let assets = generate(&Default::default()).unwrap();
+        write(&dir, &assets).unwrap();
+        let controller = dir.join("Spectrogram.controller");
+        let written = std::fs::read_to_string(&controller).unwrap();
+        // Another run would replace the GUIDs the first one gave out
+        let again = generate(&Default::default()).unwrap();
+        assert!(write(&dir, &again).is_err());
+        assert_eq!(std::fs::read_to_string(&controller).unwrap(), written);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
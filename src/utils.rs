@@ -4,16 +4,34 @@ pub fn normalize<T: Num + Copy>(v: T, min: T, max: T) -> T {
     (v - min) / (max - min)
 }
 
-pub fn lerp<T: Num + Copy>(a: T, b: T, t: T) -> T {
-    a + (b - a) * t
-}
-
 pub fn to_db<T: Float>(v: T) -> T {
     v.log10() * T::from(20.0).unwrap()
 }
 
 pub fn from_db<T: Float>(db: T) -> T {
-    T::from(20.0).unwrap().powf(db / T::from(20.0).unwrap())
+    T::from(10.0).unwrap().powf(db / T::from(20.0).unwrap())
+}
+
+/// Interval from `reference` up to `freq` in cents.
+pub fn cents<T: Float>(freq: T, reference: T) -> T {
+    (freq / reference).log2() * T::from(1200.0).unwrap()
+}
+
+pub fn midi_note_number_to_str(n: f64) -> String {
+    if !(0.0..=150.0).contains(&n) {
+        return "".into();
+    }
+    let notes = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!("{}{}", notes[n as usize % 12], n as isize / 12 - 1)
+}
+
+/// A path in the temporary directory unique to `name` and this test run, for tests that write
+/// files.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("voice-analyzer-{name}-{}", std::process::id()))
 }
 
 pub trait Elipsis {
@@ -35,6 +53,19 @@ impl Elipsis for String {
 #[cfg(test)]
 mod test {
     use super::*;
+    #[test]
+    fn test_db() {
+        assert!((to_db(10.0f32) - 20.0).abs() < 1e-5);
+        assert!((from_db(-6.0f32) - 0.501_187).abs() < 1e-5);
+        assert!((from_db(to_db(0.25f32)) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cents() {
+        assert!((cents(880.0f32, 440.0) - 1200.0).abs() < 1e-3);
+        assert!((cents(440.0f32, 466.163_8) + 100.0).abs() < 1e-3);
+    }
+
     #[test]
     fn test_elipsis() {
         let mut s = "Hello, world!".to_string();
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+/// Typical first and second formant frequencies of a vowel.
+pub struct Vowel {
+    pub symbol: &'static str,
+    pub f1: f64,
+    pub f2: f64,
+}
+
+impl Vowel {
+    const fn new(symbol: &'static str, f1: f64, f2: f64) -> Self {
+        Self { symbol, f1, f2 }
+    }
+}
+
+const MALE: [Vowel; 5] = [
+    Vowel::new("あ", 750.0, 1200.0),
+    Vowel::new("い", 300.0, 2200.0),
+    Vowel::new("う", 350.0, 1350.0),
+    Vowel::new("え", 480.0, 1900.0),
+    Vowel::new("お", 500.0, 850.0),
+];
+
+const FEMALE: [Vowel; 5] = [
+    Vowel::new("あ", 900.0, 1500.0),
+    Vowel::new("い", 350.0, 2800.0),
+    Vowel::new("う", 400.0, 1700.0),
+    Vowel::new("え", 550.0, 2350.0),
+    Vowel::new("お", 550.0, 1000.0),
+];
+
+/// Formants further than this many reference ellipse widths from every vowel are not
+/// classified.
+const MAX_DISTANCE: f64 = 3.0;
+
+/// Reference Japanese vowels drawn on the vowel chart.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VowelSet {
+    #[default]
+    Male,
+    Female,
+}
+
+impl VowelSet {
+    pub const ALL: [Self; 2] = [Self::Male, Self::Female];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Male => "男性",
+            Self::Female => "女性",
+        }
+    }
+
+    pub fn vowels(&self) -> &'static [Vowel] {
+        match self {
+            Self::Male => &MALE,
+            Self::Female => &FEMALE,
+        }
+    }
+
+    /// Half widths of the reference ellipses as (F1, F2) in Hz.
+    pub fn spread(&self) -> (f64, f64) {
+        match self {
+            Self::Male => (80.0, 200.0),
+            Self::Female => (100.0, 250.0),
+        }
+    }
+
+    /// The vowel closest to the formants, measured in reference ellipse widths. `None` when
+    /// the formants are further than `MAX_DISTANCE` from every vowel.
+    pub fn classify(&self, f1: f64, f2: f64) -> Option<&'static Vowel> {
+        let (spread1, spread2) = self.spread();
+        let distance = |v: &Vowel| ((f1 - v.f1) / spread1).hypot((f2 - v.f2) / spread2);
+        self.vowels()
+            .iter()
+            .map(|v| (v, distance(v)))
+            .filter(|&(_, d)| d <= MAX_DISTANCE)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(v, _)| v)
+    }
+}